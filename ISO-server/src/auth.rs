@@ -0,0 +1,147 @@
+use crate::{user::User, CONFIG, MEMORY_DATABASE};
+use actix_web::{dev::Payload, error::InternalError, http::header::SEC_WEBSOCKET_PROTOCOL, Error, FromRequest, HttpRequest, HttpResponse};
+use actix_web_httpauth::extractors::bearer::BearerAuth;
+use futures::future::LocalBoxFuture;
+use serde_json::json;
+
+/// The user behind an `Authorization: Bearer <token>` header.
+///
+/// Any route taking this as an argument rejects requests without a valid
+/// token with 401 before the handler runs.
+pub struct AuthedUser {
+    pub user: User,
+    /// The session the token belongs to
+    pub session_id: String,
+}
+
+/// An `AuthedUser` listed in `admins` in `config.toml`. Anyone else gets
+/// 403.
+pub struct AdminUser {
+    pub user: User,
+}
+
+/// Subprotocol that a WebSocket client names just before its token, as in
+/// `new WebSocket(url, ["bearer", token])`, since browsers can't set an
+/// `Authorization` header on one
+pub const BEARER_PROTOCOL: &str = "bearer";
+
+/// An `AuthedUser` opening a WebSocket, whose token can come from either
+/// the `Authorization` header or `Sec-WebSocket-Protocol`
+pub struct SocketUser {
+    pub user: User,
+    /// Whether the token came as a subprotocol, in which case the handshake
+    /// has to accept `BEARER_PROTOCOL`
+    pub protocol: bool,
+}
+
+fn unauthorized(message: &str) -> Error {
+    let json = json!({
+        "error": message,
+    });
+
+    InternalError::from_response(message.to_string(), HttpResponse::Unauthorized().json(json)).into()
+}
+
+fn forbidden(message: &str) -> Error {
+    let json = json!({
+        "error": message,
+    });
+
+    InternalError::from_response(message.to_string(), HttpResponse::Forbidden().json(json)).into()
+}
+
+async fn authenticate(token: &str) -> Result<AuthedUser, Error> {
+    let mut data = MEMORY_DATABASE.lock().await;
+
+    data.authenticate(token)
+        .map(|(user, session_id)| AuthedUser { user, session_id })
+        .map_err(|e| unauthorized(&e))
+}
+
+/// The token following `BEARER_PROTOCOL` in `Sec-WebSocket-Protocol`
+fn protocol_token(req: &HttpRequest) -> Option<String> {
+    let protocols = req.headers().get(SEC_WEBSOCKET_PROTOCOL)?.to_str().ok()?;
+    let mut protocols = protocols.split(',').map(str::trim);
+
+    protocols.find(|protocol| *protocol == BEARER_PROTOCOL)?;
+    protocols.next().map(String::from)
+}
+
+impl FromRequest for AuthedUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let bearer = BearerAuth::from_request(req, payload);
+
+        Box::pin(async move {
+            let bearer = bearer.await.map_err(|_| unauthorized("Missing bearer token"))?;
+
+            authenticate(bearer.token()).await
+        })
+    }
+}
+
+impl FromRequest for SocketUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let bearer = BearerAuth::from_request(req, payload);
+        let protocol_token = protocol_token(req);
+
+        Box::pin(async move {
+            let (token, protocol) = match (bearer.await, protocol_token) {
+                (Ok(bearer), _) => (bearer.token().to_string(), false),
+                (Err(_), Some(token)) => (token, true),
+                _ => return Err(unauthorized("Missing bearer token")),
+            };
+
+            let user = authenticate(&token).await?;
+            Ok(SocketUser { user: user.user, protocol })
+        })
+    }
+}
+
+impl FromRequest for AdminUser {
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self, Self::Error>>;
+
+    fn from_request(req: &HttpRequest, payload: &mut Payload) -> Self::Future {
+        let user = AuthedUser::from_request(req, payload);
+
+        Box::pin(async move {
+            let user = user.await?;
+
+            if !CONFIG.lock().await.admins.contains(&user.user.uuid) {
+                return Err(forbidden("Admins only"));
+            }
+
+            Ok(AdminUser { user: user.user })
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    fn token(protocols: Option<&str>) -> Option<String> {
+        let mut req = TestRequest::default();
+        if let Some(protocols) = protocols {
+            req = req.insert_header((SEC_WEBSOCKET_PROTOCOL, protocols));
+        }
+
+        protocol_token(&req.to_http_request())
+    }
+
+    #[test]
+    fn reads_tokens_from_subprotocols() {
+        assert_eq!(token(Some("bearer, 7QW9")).as_deref(), Some("7QW9"));
+        assert_eq!(token(Some("chat,bearer,7QW9")).as_deref(), Some("7QW9"));
+        assert_eq!(token(Some("bearer")), None);
+        assert_eq!(token(Some("chat, 7QW9")), None);
+        assert_eq!(token(None), None);
+    }
+}
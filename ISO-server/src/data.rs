@@ -1,54 +1,149 @@
-use crate::{post::{Post, PostType, TimeType}, user::User, DB_NAME, MEMORY_DATABASE};
+use crate::{events::{EventKind, FeedUpdateKind}, feed::{paginate, FeedCursor, FeedPage, FeedPageQuery}, geo::{Coordinate, GeoIndex}, karma::{transfer_for, KarmaEntry}, gazetteer::Place, message::Conversation, migrate::upgrade, post::{Post, PostEdit, PostError, PostLifetimes, PostRole, PostState}, search::SearchIndex, storage::*, tags::Tag, user::{ProfileEdit, User}, verification::VerificationBackend, DB_NAME, GAZETTEER_FILE, MEMORY_DATABASE, SQLITE_DB_NAME};
 use ::serde::{Deserialize, Serialize};
-use std::{io::{Error, Read, Write}, fs::OpenOptions, ops::Deref};
+use std::{ops::Deref, sync::Arc, time::SystemTime};
 use log::*;
 use std::collections::HashMap;
 
 pub const FEED_PAGE_SIZE: usize = 25;
 
-#[derive(Default, Deserialize, Serialize, Clone)]
+#[derive(Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Config {
     pub twilio_sid: String,
     pub twilio_service: String,
     pub twilio_token: String,
+    /// `json` or `sqlite`
+    pub storage_backend: StorageBackend,
+    pub sqlite_path: String,
+    /// Number of timestamped `db.json` backups to keep
+    pub json_backups: usize,
+    /// Seconds to wait for in-flight requests on shutdown
+    pub shutdown_timeout: u64,
+    /// `twilio`, `local` or `mock`
+    pub verification_provider: VerificationBackend,
+    /// Seconds a `local` or `mock` code stays valid
+    pub verification_code_ttl: u64,
+    /// File `mock` codes are appended to, or stdout if empty
+    pub mock_code_file: String,
+    /// Seconds a sign-in lasts
+    pub session_lifetime: u64,
+    /// Seconds between sweeps for expired posts, at least 1
+    pub expiry_sweep_interval: u64,
+    /// Default lifetime of a post per `TimeType`
+    pub post_lifetimes: PostLifetimes,
+    /// Lowest karma a user can be left with after paying for a post
+    pub karma_floor: i32,
+    /// JSON list of places loaded into the gazetteer of a new database
+    pub gazetteer_file: String,
+    /// UUIDs of users who can manage the gazetteer and tags
+    pub admins: Vec<String>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            twilio_sid: String::new(),
+            twilio_service: String::new(),
+            twilio_token: String::new(),
+            storage_backend: StorageBackend::default(),
+            sqlite_path: SQLITE_DB_NAME.to_string(),
+            json_backups: 5,
+            shutdown_timeout: 30,
+            verification_provider: VerificationBackend::default(),
+            verification_code_ttl: 600,
+            mock_code_file: String::new(),
+            session_lifetime: 30 * 86400,
+            expiry_sweep_interval: 60,
+            post_lifetimes: PostLifetimes::default(),
+            karma_floor: -5,
+            gazetteer_file: GAZETTEER_FILE.to_string(),
+            admins: Vec::new(),
+        }
+    }
 }
 
 #[derive(Default, Deserialize, Serialize, Clone)]
 #[serde(default)]
 pub struct Data {
+    /// Version of the persisted format, see `migrate`
+    pub schema_version: u32,
     pub feed: Vec<Post>,
     pub users: HashMap<String, User>,
     /// UUIDs of pinned posts
     pub pinned_posts: Vec<String>,
+    /// Every karma transfer, oldest first, see `karma`
+    pub karma_ledger: Vec<KarmaEntry>,
+    /// Messages about each post, by post UUID, see `message`
+    pub conversations: HashMap<String, Conversation>,
+    /// Named places by id, see `gazetteer`
+    pub places: HashMap<String, Place>,
+    /// Registered tags by name, see `tags`
+    pub tags: HashMap<String, Tag>,
+    /// Where mutations are persisted, set once loaded
+    #[serde(skip)]
+    pub storage: Option<Arc<dyn Storage>>,
+    /// Session token hash to user UUID, see `index_sessions`
+    #[serde(skip)]
+    pub session_index: HashMap<String, String>,
+    /// Post UUID to its position in `feed`, see `index_feed`
+    #[serde(skip)]
+    pub feed_index: HashMap<String, usize>,
+    /// Posts by location, see `index_locations`
+    #[serde(skip)]
+    pub geo_index: GeoIndex,
+    /// Posts by search term, see `index_search`
+    #[serde(skip)]
+    pub search_index: SearchIndex,
+    /// Registered tag names and synonyms to their tag, see `index_tags`
+    #[serde(skip)]
+    pub tag_synonyms: HashMap<String, String>,
+}
+
+/// Seconds since the Unix epoch
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_secs()
 }
 
 impl Data {
-    pub fn get_feed_page(&self, index: usize) -> Result<Vec<Post>, String> {
-        if index >= self.feed.len() {
-            return Err("Index out of bounds".to_string());
+    /// Hand a mutation to the storage backend, if there is one
+    pub(crate) fn persist<F: FnOnce(&dyn Storage) -> Result<(), String>>(&self, f: F) -> Result<(), String> {
+        match &self.storage {
+            Some(storage) => f(storage.as_ref()),
+            None => Ok(()),
         }
+    }
 
-        let mut page = Vec::new();
-
-        for i in index..std::cmp::min(index + FEED_PAGE_SIZE, self.feed.len()) {
-            page.push(self.feed[i].clone());
-        }
+    /// Rebuild `feed_index` from the feed
+    pub fn index_feed(&mut self) {
+        self.feed_index = self.feed.iter().enumerate().map(|(i, post)| (post.uuid.clone(), i)).collect();
+    }
 
-        Ok(page)
+    /// A post in the feed by UUID, without scanning the feed
+    pub fn indexed_post(&self, uuid: &str) -> Option<&Post> {
+        self.feed_index.get(uuid).and_then(|i| self.feed.get(*i))
     }
 
-    pub fn get_user_by_token(&self, uuid: &str, token: &str) -> Result<User, String> {
-        if self.users.contains_key(uuid) {
-            let user = self.users.get(uuid).unwrap();
-            if user.get_token() == token {
-                return Ok(user.clone());
-            } else {
-                return Err("Invalid token".to_string());
-            }
-        } else {
-            return Err("User not found".to_string());
-        }
+    /// A page of the posts `uuid` wrote or claimed, newest first
+    pub fn get_user_posts(&self, uuid: &str, role: PostRole, state: Option<PostState>, query: &FeedPageQuery) -> Result<FeedPage, String> {
+        let user = self.users.get(uuid).ok_or("User not found".to_string())?;
+
+        let uuids = match role {
+            PostRole::Owner => user.get_posts(),
+            PostRole::Acceptor => user.get_claimed_posts(),
+        };
+
+        let mut posts: Vec<(FeedCursor, &Post)> = uuids.iter()
+            .filter_map(|uuid| self.indexed_post(uuid))
+            .filter(|post| state.map_or(true, |state| post.state == state))
+            .map(|post| (FeedCursor::of(post), post))
+            .collect();
+
+        posts.sort_by(|a, b| b.0.cmp(&a.0));
+
+        paginate(&posts, query, FEED_PAGE_SIZE)
     }
 
     pub fn get_user_by_number(&mut self, phone_number: &String) -> Result<&mut User, String> {
@@ -65,13 +160,34 @@ impl Data {
         if self.users.contains_key(&user.uuid) {
             return Err("User already exists".to_string());
         } else {
+            self.persist(|s| s.add_user(&user))?;
             self.users.insert(user.uuid.clone(), user);
             return Ok(());
         }
     }
     
-    pub fn add_update_user(&mut self, user: User) {
+    pub fn add_update_user(&mut self, user: User) -> Result<(), String> {
+        self.persist(|s| s.update_user(&user))?;
         self.users.insert(user.uuid.clone(), user);
+        Ok(())
+    }
+
+    pub fn set_location(&mut self, uuid: &str, location: Coordinate) -> Result<User, String> {
+        location.check()?;
+
+        let mut user = self.users.get(uuid).cloned().ok_or("User not found".to_string())?;
+        user.set_location(location);
+
+        self.add_update_user(user.clone())?;
+        Ok(user)
+    }
+
+    pub fn edit_profile(&mut self, uuid: &str, edit: ProfileEdit) -> Result<User, String> {
+        let mut user = self.users.get(uuid).cloned().ok_or("User not found".to_string())?;
+        user.edit_profile(edit)?;
+
+        self.add_update_user(user.clone())?;
+        Ok(user)
     }
 
     pub async fn check_verification(&mut self, uuid: String, code: String) -> Result<User, String> {
@@ -84,7 +200,7 @@ impl Data {
                 return Err(user.err().unwrap());
             } else {
                 let user = user.unwrap();
-                self.add_update_user(user.clone());
+                self.add_update_user(user.clone())?;
                 return Ok(user);
             }
         } else {
@@ -92,20 +208,42 @@ impl Data {
         }
     }
 
-    pub async fn add_post(&mut self, title: String, post_type: PostType, owner_uuid: String, time_type: TimeType, tags: Vec<String>, location_string: String) -> Result<(), String> {
-        if self.users.contains_key(&owner_uuid) || true {
-            let post = Post::new(title, post_type, owner_uuid.clone(), time_type, tags, location_string);
+    /// Add a new post from `Post::new`, publishing it unless it's a draft
+    pub async fn add_post(&mut self, mut post: Post, draft: bool, lifetimes: &PostLifetimes) -> Result<(), String> {
+        let owner_uuid = post.get_owner();
 
-            let user = self.users.get(&owner_uuid).clone();
+        if let Some(location) = post.location {
+            location.check()?;
+        }
+
+        if self.users.contains_key(&owner_uuid) {
+            post.set_tags(self.normalize_tags(post.get_tags())?);
+            self.geocode(&mut post);
+
+            if !draft {
+                let lifetime = post.lifetime(lifetimes);
+                post.publish(&owner_uuid, lifetime).map_err(|e| e.to_string())?;
+            }
 
-            if user.is_some() {
-                let mut user = user.unwrap().clone();
+            let user = self.users.get(&owner_uuid).cloned().map(|mut user| {
                 user.add_post(post.uuid.clone());
-                
-                self.add_update_user(user.clone());
+                user
+            });
+
+            self.persist(|s| s.add_post(&post, user.as_ref()))?;
+
+            if let Some(user) = user {
+                self.users.insert(user.uuid.clone(), user);
             }
 
             self.feed.insert(0, post.clone());
+            self.index_feed();
+            self.geo_index.update(&post);
+            self.search_index.update(&post);
+
+            if !draft {
+                self.emit_feed(FeedUpdateKind::Posted, &post);
+            }
 
             return Ok(());
         } else {
@@ -123,70 +261,339 @@ impl Data {
         Err("Post not found".to_string())
     }
 
-    pub fn claim_post(&mut self, uuid: String, user: User) -> Result<(), String> {
-       // Auth with user uuid and token
-         if self.users.contains_key(&user.uuid) {
-              let mut db_user = self.users.get(&user.uuid).unwrap().clone();
-              if db_user.get_token() != user.get_token() {
-                return Err("Invalid token".to_string());
-              } else {
-                // Get post
-                let post = self.get_post_by_uuid(&uuid);
-                if post.is_err() {
-                    return Err(post.err().unwrap());
-                } else {
-                    let mut post = post.unwrap();
-                    post.claim(user.uuid.clone());
-
-                    db_user.add_claimed_post(post.uuid.clone());
-
-                    self.users.insert(user.uuid, db_user.clone());
-
-                    let pos = self.feed.iter().position(|x| x.uuid == uuid);
-                    self.feed[pos.unwrap()] = post;
-                    return Ok(());
+    /// Offer to fulfill a post, pending the owner's approval
+    pub fn claim_post(&mut self, uuid: String, user_uuid: &str) -> Result<(), String> {
+        if !self.users.contains_key(user_uuid) {
+            return Err("User not found".to_string());
+        }
+
+        let mut post = self.get_post_by_uuid(&uuid)?;
+        post.request_claim(user_uuid.to_string()).map_err(|e| e.to_string())?;
+
+        let mut users = Vec::new();
+        if let Some(mut owner) = self.users.get(&post.get_owner()).cloned() {
+            owner.notify(uuid.clone(), format!("Someone offered to claim \"{}\"", post.title));
+            users.push(owner);
+        }
+
+        let owner_uuid = post.get_owner();
+        self.update_post(post, users)?;
+        self.emit(&owner_uuid, EventKind::ClaimRequested { post_uuid: uuid, user_uuid: user_uuid.to_string() });
+
+        Ok(())
+    }
+
+    pub fn withdraw_claim(&mut self, uuid: &str, user_uuid: &str) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        post.withdraw_claim(user_uuid).map_err(|e| e.to_string())?;
+
+        self.update_post(post.clone(), Vec::new())?;
+        self.emit(&post.get_owner(), EventKind::ClaimWithdrawn { post_uuid: uuid.to_string(), user_uuid: user_uuid.to_string() });
+        Ok(post)
+    }
+
+    /// The owner accepts one offer, turning down the rest. Whoever will pay
+    /// for the post must be able to afford it, on top of the posts they
+    /// already have to pay for.
+    pub fn approve_claim(&mut self, uuid: &str, owner_uuid: &str, acceptor_uuid: &str, karma_floor: i32) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        let mut acceptor = self.users.get(acceptor_uuid).cloned().ok_or("User not found".to_string())?;
+
+        let declined = post.approve_claim(owner_uuid, acceptor_uuid).map_err(|e| e.to_string())?;
+
+        if let Some((payer, _, amount)) = transfer_for(&post) {
+            self.check_karma_floor(&payer, amount, karma_floor)?;
+        }
+
+        acceptor.add_claimed_post(uuid.to_string());
+        acceptor.notify(uuid.to_string(), format!("Your offer on \"{}\" was accepted", post.title));
+
+        let mut users = vec![acceptor];
+        for uuid in declined.iter() {
+            if let Some(mut user) = self.users.get(uuid).cloned() {
+                user.notify(post.uuid.clone(), format!("\"{}\" was claimed by someone else", post.title));
+                users.push(user);
+            }
+        }
+
+        self.update_post(post.clone(), users)?;
+
+        self.emit_feed(FeedUpdateKind::Claimed, &post);
+        self.emit(acceptor_uuid, EventKind::ClaimApproved { post_uuid: uuid.to_string() });
+        for declined in declined.iter() {
+            self.emit(declined, EventKind::ClaimDeclined { post_uuid: uuid.to_string() });
+        }
+        self.emit_post_state(&post);
+
+        Ok(post)
+    }
+
+    /// Replace a post and any users it touched, as one mutation
+    pub fn update_post(&mut self, post: Post, users: Vec<User>) -> Result<(), String> {
+        let pos = self.feed.iter().position(|x| x.uuid == post.uuid).ok_or("Post not found".to_string())?;
+
+        self.persist(|s| s.update_post(&post, &users))?;
+
+        for user in users {
+            self.users.insert(user.uuid.clone(), user);
+        }
+        self.geo_index.update(&post);
+        self.search_index.update(&post);
+        self.feed[pos] = post;
+
+        Ok(())
+    }
+
+    pub fn publish_post(&mut self, uuid: &str, user_uuid: &str, lifetimes: &PostLifetimes) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        let lifetime = post.lifetime(lifetimes);
+        post.publish(user_uuid, lifetime).map_err(|e| e.to_string())?;
+
+        self.update_post(post.clone(), Vec::new())?;
+        self.emit_feed(FeedUpdateKind::Posted, &post);
+        self.emit_post_state(&post);
+        Ok(post)
+    }
+
+    pub fn cancel_post(&mut self, uuid: &str, user_uuid: &str) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        // Drafts never reached the feed, so streams shouldn't hear of them
+        let listed = post.state == PostState::Posted;
+        post.cancel(user_uuid).map_err(|e| e.to_string())?;
+
+        self.update_post(post.clone(), Vec::new())?;
+        if listed {
+            self.emit_feed(FeedUpdateKind::Removed, &post);
+        }
+        self.emit_post_state(&post);
+        Ok(post)
+    }
+
+    /// Confirm a post is done, paying out karma once both sides have
+    pub fn complete_post(&mut self, uuid: &str, user_uuid: &str) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        post.complete(user_uuid).map_err(|e| e.to_string())?;
+
+        if post.state != PostState::Completed {
+            self.update_post(post.clone(), Vec::new())?;
+            return Ok(post);
+        }
+
+        let (entry, users) = self.settle_post(&post)?;
+        let pos = self.feed.iter().position(|x| x.uuid == post.uuid).ok_or("Post not found".to_string())?;
+
+        self.persist(|s| s.settle_post(&post, &users, &entry))?;
+
+        for user in users {
+            self.users.insert(user.uuid.clone(), user);
+        }
+        self.geo_index.update(&post);
+        self.search_index.update(&post);
+        self.feed[pos] = post.clone();
+
+        for uuid in [&entry.from, &entry.to].into_iter().flatten() {
+            if let Some(user) = self.users.get(uuid) {
+                self.emit(uuid, EventKind::KarmaChanged { karma: user.get_karma(), delta: entry.delta_for(uuid), post_uuid: entry.post_uuid.clone() });
+            }
+        }
+        self.emit_post_state(&post);
+        self.karma_ledger.push(entry);
+
+        Ok(post)
+    }
+
+    pub fn reopen_post(&mut self, uuid: &str, user_uuid: &str, lifetimes: &PostLifetimes) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        let lifetime = post.lifetime(lifetimes);
+        let acceptor = post.reopen(user_uuid, lifetime).map_err(|e| e.to_string())?;
+
+        // The post no longer counts as claimed by its old acceptor
+        let mut users = Vec::new();
+        if let Some(mut acceptor) = acceptor.and_then(|uuid| self.users.get(&uuid).cloned()) {
+            acceptor.remove_claimed_post(uuid);
+            users.push(acceptor);
+        }
+
+        self.update_post(post.clone(), users.clone())?;
+
+        self.emit_feed(FeedUpdateKind::Posted, &post);
+
+        // The old acceptor is no longer on the post, so isn't covered below
+        for acceptor in users.iter() {
+            self.emit(&acceptor.uuid, EventKind::PostStateChanged { post_uuid: uuid.to_string(), state: post.state });
+        }
+        self.emit_post_state(&post);
+
+        Ok(post)
+    }
+
+    /// Extend a post by `seconds`, or its default lifetime if 0
+    pub fn renew_post(&mut self, uuid: &str, user_uuid: &str, seconds: u64, lifetimes: &PostLifetimes) -> Result<Post, String> {
+        let mut post = self.get_post_by_uuid(uuid)?;
+        let lifetime = if seconds == 0 { post.lifetime(lifetimes) } else { seconds };
+        post.renew(user_uuid, lifetime).map_err(|e| e.to_string())?;
+
+        self.update_post(post.clone(), Vec::new())?;
+        self.emit_feed(FeedUpdateKind::Posted, &post);
+        self.emit_post_state(&post);
+        Ok(post)
+    }
+
+    pub fn edit_post(&mut self, uuid: &str, user_uuid: &str, mut edit: PostEdit) -> Result<Post, String> {
+        edit.tags = edit.tags.map(|tags| self.normalize_tags(&tags)).transpose()?;
+
+        if let Some(location) = edit.location {
+            location.check()?;
+        }
+
+        let mut post = self.get_post_by_uuid(uuid)?;
+        let location_string = post.location_string.clone();
+        post.edit(user_uuid, edit).map_err(|e| e.to_string())?;
+
+        if post.location_string != location_string {
+            self.geocode(&mut post);
+        }
+
+        self.update_post(post.clone(), Vec::new())?;
+        if post.state == PostState::Posted {
+            self.emit_feed(FeedUpdateKind::Edited, &post);
+        }
+        Ok(post)
+    }
+
+    /// Remove a post from the feed and from its owner and claimer, along
+    /// with its messages
+    pub fn delete_post(&mut self, uuid: &str, user_uuid: &str) -> Result<(), String> {
+        let post = self.get_post_by_uuid(uuid)?;
+
+        if post.get_owner() != user_uuid {
+            return Err(PostError::NotOwner.to_string());
+        }
+
+        let mut users = Vec::new();
+
+        if let Some(mut owner) = self.users.get(user_uuid).cloned() {
+            owner.remove_post(uuid);
+            users.push(owner);
+        }
+
+        if let Some(mut acceptor) = post.get_acceptor().and_then(|uuid| self.users.get(&uuid).cloned()) {
+            acceptor.remove_claimed_post(uuid);
+            if post.state == PostState::Accepted {
+                acceptor.notify(uuid.to_string(), format!("\"{}\" was deleted by its owner", post.title));
+            }
+            users.push(acceptor);
+        }
+
+        self.persist(|s| s.delete_post(uuid, &users))?;
+
+        for user in users {
+            self.users.insert(user.uuid.clone(), user);
+        }
+        self.feed.retain(|x| x.uuid != uuid);
+        self.index_feed();
+        self.pinned_posts.retain(|x| x != uuid);
+        self.conversations.remove(uuid);
+        self.geo_index.remove(uuid);
+        self.search_index.remove(uuid);
+
+        if post.state == PostState::Posted {
+            self.emit_feed(FeedUpdateKind::Removed, &post);
+        }
+        if let Some(acceptor) = post.get_acceptor() {
+            self.emit(&acceptor, EventKind::PostDeleted { post_uuid: uuid.to_string() });
+        }
+
+        Ok(())
+    }
+
+    /// Move every overdue `Posted` post to `Expired`, returning their UUIDs
+    pub fn expire_posts(&mut self) -> Vec<String> {
+        let overdue: Vec<Post> = self.feed.iter()
+            .filter(|post| post.state == PostState::Posted && post.is_expired())
+            .cloned()
+            .collect();
+
+        let mut expired = Vec::new();
+
+        for mut post in overdue {
+            if post.expire().is_err() {
+                continue;
+            }
+
+            let uuid = post.uuid.clone();
+            match self.update_post(post.clone(), Vec::new()) {
+                Ok(()) => {
+                    self.emit_feed(FeedUpdateKind::Expired, &post);
+                    self.emit_post_state(&post);
+                    expired.push(uuid);
                 }
-              }
-         } else {
-              return Err("User not found".to_string());
-         }
+                Err(e) => error!("Failed to expire post {}: {}", uuid, e),
+            }
+        }
+
+        expired
     }
 }
 
-fn from_slice_lenient<'a, T: ::serde::Deserialize<'a>>(
-    v: &'a [u8],
-) -> Result<T, serde_json::Error> {
-    let mut cur = std::io::Cursor::new(v);
-    let mut de = serde_json::Deserializer::new(serde_json::de::IoRead::new(&mut cur));
-    ::serde::Deserialize::deserialize(&mut de)
-    // note the lack of: de.end()
+/// Open the storage backend selected in `config.toml`
+pub fn open_storage(config: &Config) -> Result<Arc<dyn Storage>, String> {
+    match config.storage_backend {
+        StorageBackend::Json => Ok(Arc::new(JsonStorage::open(DB_NAME, config.json_backups)?)),
+        StorageBackend::Sqlite => {
+            let storage = SqliteStorage::open(&config.sqlite_path)?;
+            if storage.import_json(DB_NAME)? {
+                info!("Imported {} into {}", DB_NAME, config.sqlite_path);
+            }
+            Ok(Arc::new(storage))
+        }
+    }
 }
 
-pub fn load_database() -> Result<Data, Error> {
-    let file = OpenOptions::new().read(true).open(DB_NAME);
-
-    if file.is_err() {
-        Ok(Data::default())
-    } else {
-        let mut file = file.unwrap();
+/// The document `open_storage` and `load_database` would start from, read
+/// without creating, importing or replaying anything into storage
+pub fn preview_document(config: &Config) -> Result<serde_json::Value, String> {
+    match config.storage_backend {
+        StorageBackend::Json => JsonStorage::read(DB_NAME),
+        StorageBackend::Sqlite => match SqliteStorage::open_read_only(&config.sqlite_path)? {
+            Some(storage) if !storage.is_empty()? => storage.load_document(),
+            // What the first start would import
+            _ => JsonStorage::read(DB_NAME),
+        },
+    }
+}
 
-        let mut data = String::new();
-        file.read_to_string(&mut data)?;
-        let data: Data = from_slice_lenient(data.as_bytes()).unwrap();
-        Ok(data)
+pub fn load_database(storage: Arc<dyn Storage>, config: &Config) -> Result<Data, String> {
+    let mut document = storage.load_document()?;
+    let report = upgrade(&mut document, config)?;
+
+    let mut data: Data = serde_json::from_value(document).map_err(|e| e.to_string())?;
+    data.index_sessions();
+    data.index_karma();
+    data.index_feed();
+    data.index_locations();
+    data.index_search();
+    data.index_tags();
+
+    if !report.is_empty() {
+        info!("Migrated database from schema version {} to {}", report.from, report.to);
+        storage.save(&data)?;
     }
+
+    data.storage = Some(storage);
+    Ok(data)
 }
 
-pub async fn save_database() -> Result<(), Error> {
+pub async fn save_database() -> Result<(), String> {
     info!("Saving database...");
-    let mut file = OpenOptions::new().write(true).create(true).open(DB_NAME)?;
     let data = MEMORY_DATABASE.lock().await;
 
     // Get data struct from mutex guard
     let data = data.deref();
 
-    let data = serde_json::to_string_pretty(&data)?;
-    file.write_all(data.as_bytes())?;
+    if let Some(storage) = &data.storage {
+        storage.save(data)?;
+    }
     info!("Database saved.");
     Ok(())
 }
@@ -0,0 +1,205 @@
+use crate::{data::{now, Data}, message::Message, post::{Post, PostState}, view::PostView};
+use ::serde::{Deserialize, Serialize};
+use lazy_static::lazy_static;
+use std::{collections::{HashMap, VecDeque}, sync::Mutex, time::SystemTime};
+use tokio::sync::{broadcast, mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender}, watch};
+
+/// Events kept per user for replay after a reconnect
+pub const EVENT_BACKLOG: usize = 100;
+/// Feed updates a slow stream reader can fall behind by before missing some
+pub const FEED_BACKLOG: usize = 256;
+
+lazy_static! {
+    pub static ref EVENTS: EventBus = EventBus::new();
+    pub static ref FEED: broadcast::Sender<FeedUpdate> = broadcast::channel(FEED_BACKLOG).0;
+    /// Set once the server starts shutting down, so long-lived connections
+    /// close instead of holding up the drain
+    pub static ref SHUTDOWN: watch::Sender<bool> = watch::channel(false).0;
+}
+
+/// Something a signed-in client should hear about right away
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(tag = "type", content = "payload")]
+pub enum EventKind {
+    MessageReceived { message: Message },
+    ClaimRequested { post_uuid: String, user_uuid: String },
+    ClaimWithdrawn { post_uuid: String, user_uuid: String },
+    ClaimApproved { post_uuid: String },
+    ClaimDeclined { post_uuid: String },
+    PostStateChanged { post_uuid: String, state: PostState },
+    PostDeleted { post_uuid: String },
+    KarmaChanged { karma: i32, delta: i32, post_uuid: Option<String> },
+    /// The requested cursor is too old to replay; refetch over HTTP
+    Resync,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub struct UserEvent {
+    /// Increases with every event, across users and restarts
+    pub seq: u64,
+    pub time: u64,
+    #[serde(flatten)]
+    pub kind: EventKind,
+}
+
+#[derive(Default)]
+struct Mailbox {
+    /// Oldest first
+    backlog: VecDeque<UserEvent>,
+    /// `seq` of the newest event dropped from the backlog
+    dropped: u64,
+    subscribers: Vec<UnboundedSender<UserEvent>>,
+}
+
+/// Fans events out to each user's open connections.
+///
+/// Nothing here is persisted. Sequence numbers start from the startup time
+/// in microseconds, so cursors from before a restart are always older than
+/// anything retained and get a `Resync`.
+pub struct EventBus {
+    /// First `seq` handed out by this process
+    start: u64,
+    next_seq: Mutex<u64>,
+    mailboxes: Mutex<HashMap<String, Mailbox>>,
+}
+
+impl EventBus {
+    fn new() -> EventBus {
+        let start = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_micros() as u64;
+
+        EventBus {
+            start,
+            next_seq: Mutex::new(start),
+            mailboxes: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn publish(&self, user_uuid: &str, kind: EventKind) {
+        let mut next_seq = self.next_seq.lock().unwrap();
+        let event = UserEvent { seq: *next_seq, time: now(), kind };
+        *next_seq += 1;
+
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.entry(user_uuid.to_string()).or_default();
+
+        mailbox.subscribers.retain(|subscriber| subscriber.send(event.clone()).is_ok());
+
+        mailbox.backlog.push_back(event);
+        if mailbox.backlog.len() > EVENT_BACKLOG {
+            if let Some(dropped) = mailbox.backlog.pop_front() {
+                mailbox.dropped = dropped.seq;
+            }
+        }
+    }
+
+    /// Listen for `user_uuid`'s events, first replaying everything after
+    /// `cursor` if given
+    pub fn subscribe(&self, user_uuid: &str, cursor: Option<u64>) -> UnboundedReceiver<UserEvent> {
+        let (sender, receiver) = unbounded_channel();
+        // Before `mailboxes`, the order `publish` takes them in
+        let next_seq = *self.next_seq.lock().unwrap();
+
+        let mut mailboxes = self.mailboxes.lock().unwrap();
+        let mailbox = mailboxes.entry(user_uuid.to_string()).or_default();
+
+        if let Some(cursor) = cursor {
+            // From before a restart, past events that were dropped, or made up
+            let missed = cursor < self.start || cursor < mailbox.dropped || cursor >= next_seq;
+
+            if missed {
+                let _ = sender.send(UserEvent { seq: cursor, time: now(), kind: EventKind::Resync });
+            } else {
+                for event in mailbox.backlog.iter().filter(|event| event.seq > cursor) {
+                    let _ = sender.send(event.clone());
+                }
+            }
+        }
+
+        mailbox.subscribers.push(sender);
+        receiver
+    }
+}
+
+/// How a post changed on the public feed
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub enum FeedUpdateKind {
+    /// New, republished or renewed
+    Posted,
+    Edited,
+    Claimed,
+    Expired,
+    /// Cancelled or deleted
+    Removed,
+}
+
+#[derive(Clone, Debug)]
+pub struct FeedUpdate {
+    pub kind: FeedUpdateKind,
+    /// For filtering; never sent
+    pub post: Post,
+    pub view: PostView,
+}
+
+impl Data {
+    /// Tell feed streams about a change to `post`
+    pub(crate) fn emit_feed(&self, kind: FeedUpdateKind, post: &Post) {
+        let view = PostView::new(self, post, None);
+
+        // Fails only when nobody is listening
+        let _ = FEED.send(FeedUpdate { kind, post: post.clone(), view });
+    }
+
+    pub(crate) fn emit(&self, user_uuid: &str, kind: EventKind) {
+        EVENTS.publish(user_uuid, kind);
+    }
+
+    /// Tell the owner and acceptor of `post` that its state changed
+    pub(crate) fn emit_post_state(&self, post: &Post) {
+        for uuid in std::iter::once(post.get_owner()).chain(post.get_acceptor()) {
+            self.emit(&uuid, EventKind::PostStateChanged { post_uuid: post.uuid.clone(), state: post.state });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{post::{PostType, TimeType}, user::User};
+
+    #[test]
+    fn drafts_never_reach_the_feed_stream() {
+        let mut feed = FEED.subscribe();
+
+        let mut data = Data::default();
+        data.users.insert("owner".to_string(), User::new("owner".to_string(), String::new()).unwrap());
+
+        let mut uuids = Vec::new();
+        for published in [false, true, false, true] {
+            let mut post = Post::new("Post".to_string(), PostType::ISO, "owner".to_string(), TimeType::ServiceNow, vec![], String::new());
+            if published {
+                post.publish("owner", 3600).unwrap();
+            }
+            uuids.push(post.uuid.clone());
+            data.feed.push(post);
+        }
+        data.index_feed();
+
+        data.cancel_post(&uuids[0], "owner").unwrap();
+        data.cancel_post(&uuids[1], "owner").unwrap();
+        data.delete_post(&uuids[2], "owner").unwrap();
+        data.delete_post(&uuids[3], "owner").unwrap();
+
+        // Other tests share the stream, so only look at these posts
+        let mut removed = Vec::new();
+        while let Ok(update) = feed.try_recv() {
+            if uuids.contains(&update.post.uuid) {
+                assert_eq!(update.kind, FeedUpdateKind::Removed);
+                removed.push(update.post.uuid);
+            }
+        }
+        assert_eq!(removed, vec![uuids[1].clone(), uuids[3].clone()]);
+    }
+}
@@ -0,0 +1,352 @@
+use crate::{data::{Data, FEED_PAGE_SIZE}, post::{FeedFilter, Post, PostState}};
+use ::serde::{Deserialize, Serialize};
+
+/// Largest page a client can ask for
+pub const MAX_FEED_PAGE_SIZE: usize = 100;
+
+/// A position in the feed, between posts.
+///
+/// The feed is ordered newest first by `(time_posted, uuid)`, so a cursor
+/// stays valid however many posts are added or removed around it. Clients
+/// get it as an opaque string.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct FeedCursor {
+    pub time_posted: u64,
+    pub uuid: String,
+}
+
+/// A position in a listing of posts, handed to clients as an opaque string
+pub trait PageCursor: Ord + Sized {
+    fn encode(&self) -> String;
+    fn decode(cursor: &str) -> Result<Self, String>;
+}
+
+/// Hex, so cursors are safe in a query string
+pub(crate) fn to_hex(s: &str) -> String {
+    s.bytes().map(|b| format!("{:02x}", b)).collect()
+}
+
+pub(crate) fn from_hex(hex: &str) -> Option<String> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|i| hex.get(i..i + 2).and_then(|pair| u8::from_str_radix(pair, 16).ok()))
+        .collect::<Option<Vec<u8>>>()?;
+
+    String::from_utf8(bytes).ok()
+}
+
+impl FeedCursor {
+    pub fn of(post: &Post) -> FeedCursor {
+        FeedCursor { time_posted: post.time_posted, uuid: post.uuid.clone() }
+    }
+}
+
+impl PageCursor for FeedCursor {
+    fn encode(&self) -> String {
+        to_hex(&format!("{}:{}", self.time_posted, self.uuid))
+    }
+
+    fn decode(cursor: &str) -> Result<FeedCursor, String> {
+        let invalid = || "Invalid cursor".to_string();
+
+        let decoded = from_hex(cursor).ok_or_else(invalid)?;
+        let (time_posted, uuid) = decoded.split_once(':').ok_or_else(invalid)?;
+
+        Ok(FeedCursor {
+            time_posted: time_posted.parse().map_err(|_| invalid())?,
+            uuid: uuid.to_string(),
+        })
+    }
+}
+
+impl FeedFilter {
+    /// Whether `post` belongs in a feed listing. Without a `state`, only
+    /// live posts are included.
+    pub fn shows(&self, post: &Post) -> bool {
+        let live = match self.state {
+            None | Some(PostState::Posted) => post.state == PostState::Posted && !post.is_expired(),
+            // Including ones the sweeper hasn't got to yet
+            Some(PostState::Expired) => post.is_expired(),
+            Some(state) => post.state == state,
+        };
+
+        live && self.matches_fields(post)
+    }
+}
+
+/// Where to start a page, from the query string
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct FeedPageQuery {
+    /// Items older than this cursor
+    pub after: Option<String>,
+    /// Items newer than this cursor
+    pub before: Option<String>,
+    /// Page size, up to `MAX_FEED_PAGE_SIZE`
+    pub limit: Option<usize>,
+}
+
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Cursor for the next (older) page, if there is one
+    pub next: Option<String>,
+    /// Cursor for the previous (newer) page, including items that arrive later
+    pub prev: Option<String>,
+}
+
+pub type FeedPage = Page<Post>;
+
+impl Data {
+    /// Posts matching `filter`, newest first
+    pub fn visible_posts(&self, filter: &FeedFilter) -> Vec<&Post> {
+        let mut posts: Vec<&Post> = self.feed.iter().filter(|post| filter.shows(post)).collect();
+
+        posts.sort_by_key(|post| std::cmp::Reverse(FeedCursor::of(post)));
+        posts
+    }
+
+    pub fn get_feed(&self, filter: &FeedFilter, query: &FeedPageQuery) -> Result<FeedPage, String> {
+        filter.check()?;
+
+        let posts: Vec<(FeedCursor, &Post)> = self.visible_posts(filter).into_iter().map(|post| (FeedCursor::of(post), post)).collect();
+
+        paginate(&posts, query, FEED_PAGE_SIZE)
+    }
+}
+
+/// The page of `items` that `query` asks for, `default_limit` long unless
+/// it says otherwise. `items` must be sorted by descending cursor.
+pub fn paginate<C: PageCursor, T: Clone>(items: &[(C, &T)], query: &FeedPageQuery, default_limit: usize) -> Result<Page<T>, String> {
+    if query.after.is_some() && query.before.is_some() {
+        return Err("Only one of after and before can be given".to_string());
+    }
+
+    let limit = query.limit.unwrap_or(default_limit);
+    if limit == 0 || limit > MAX_FEED_PAGE_SIZE {
+        return Err(format!("limit must be between 1 and {}", MAX_FEED_PAGE_SIZE));
+    }
+
+    let after = query.after.as_deref().map(C::decode).transpose()?;
+    let before = query.before.as_deref().map(C::decode).transpose()?;
+
+    // The page is `items[start..end]`
+    let (start, end) = match (&after, &before) {
+        (Some(after), _) => {
+            let start = items.partition_point(|(cursor, _)| cursor >= after);
+            (start, std::cmp::min(start.saturating_add(limit), items.len()))
+        }
+        (_, Some(before)) => {
+            let end = items.partition_point(|(cursor, _)| cursor > before);
+            (end.saturating_sub(limit), end)
+        }
+        _ => (0, std::cmp::min(limit, items.len())),
+    };
+
+    let page = &items[start..end];
+
+    let next = match page.last() {
+        Some((cursor, _)) if end < items.len() => Some(cursor.encode()),
+        _ => None,
+    };
+
+    // An empty page can still page back to where it was asked from
+    let prev = match page.first() {
+        Some((cursor, _)) => Some(cursor.encode()),
+        None => after.or(before).map(|cursor| cursor.encode()),
+    };
+
+    Ok(Page { items: page.iter().map(|(_, item)| (*item).clone()).collect(), next, prev })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{post::{PostRole, PostType, TagMode, TimeType}, user::User};
+
+    const SEEDED: usize = 60;
+    const OWNERS: [&str; 3] = ["alice", "bob", "carol"];
+    const TIME_TYPES: [TimeType; 4] = [TimeType::ServiceNow, TimeType::ServiceFuture, TimeType::ItemPermanant, TimeType::ItemLoan];
+
+    /// Published posts whose fields cycle with their index: type by 2, owner
+    /// by 3, time type by 4, tags by 5 and state by 7. Plus a draft.
+    fn seed() -> Data {
+        let mut data = Data::default();
+
+        for i in 0..SEEDED {
+            let post_type = if i % 2 == 0 { PostType::ISO } else { PostType::OSI };
+            let tags = match i % 5 {
+                0 => vec!["tools", "garden"],
+                1 => vec!["tools"],
+                2 => vec!["garden"],
+                _ => vec![],
+            };
+
+            let mut post = Post::new(format!("Post {}", i), post_type, OWNERS[i % 3].to_string(), TIME_TYPES[i % 4].clone(), tags.into_iter().map(String::from).collect(), String::new());
+            post.publish(OWNERS[i % 3], 3600).unwrap();
+            // Pairs share a time, so ties are broken by UUID
+            post.time_posted = 1000 + i as u64 / 2;
+
+            match i % 7 {
+                5 => post.time_expires = 1,
+                6 => post.state = PostState::Completed,
+                _ => {}
+            }
+
+            data.feed.push(post);
+        }
+
+        data.feed.push(Post::new("Draft".to_string(), PostType::ISO, "alice".to_string(), TimeType::ItemLoan, vec!["tools".to_string()], String::new()));
+        data
+    }
+
+    fn index(post: &Post) -> usize {
+        post.title.trim_start_matches("Post ").parse().unwrap()
+    }
+
+    fn live(i: usize) -> bool {
+        i % 7 < 5
+    }
+
+    fn uuids(posts: &[Post]) -> Vec<&str> {
+        posts.iter().map(|post| post.uuid.as_str()).collect()
+    }
+
+    /// Every post `filter` lists, read `limit` at a time. Checks that paging
+    /// back gives the same pages.
+    fn read_all(data: &Data, filter: &FeedFilter, limit: usize) -> Vec<Post> {
+        let page = |after: Option<String>, before: Option<String>| {
+            data.get_feed(filter, &FeedPageQuery { after, before, limit: Some(limit) }).unwrap()
+        };
+
+        let mut pages = vec![page(None, None)];
+        while let Some(next) = pages.last().unwrap().next.clone() {
+            pages.push(page(Some(next), None));
+        }
+
+        for full in pages[..pages.len() - 1].iter() {
+            assert_eq!(full.items.len(), limit, "short page for {:?}", filter);
+        }
+
+        let mut before = pages.last().unwrap().prev.clone();
+        for expected in pages.iter().rev().skip(1) {
+            let previous = page(None, before);
+            assert_eq!(uuids(&previous.items), uuids(&expected.items));
+            before = previous.prev;
+        }
+        if before.is_some() {
+            assert!(page(None, before).items.is_empty());
+        }
+
+        let posts: Vec<Post> = pages.into_iter().flat_map(|page| page.items).collect();
+
+        // Strictly newest first, so no post is on two pages
+        assert!(posts.windows(2).all(|pair| FeedCursor::of(&pair[0]) > FeedCursor::of(&pair[1])));
+        posts
+    }
+
+    fn check(filter: FeedFilter, expected: impl Fn(usize) -> bool) {
+        let data = seed();
+
+        let mut found: Vec<usize> = read_all(&data, &filter, 7).iter().map(index).collect();
+        found.sort();
+
+        assert_eq!(found, (0..SEEDED).filter(|i| expected(*i)).collect::<Vec<usize>>(), "{:?}", filter);
+    }
+
+    #[test]
+    fn unfiltered_feed_is_live_posts() {
+        check(FeedFilter::default(), live);
+
+        let data = seed();
+        for limit in 1..=10 {
+            assert_eq!(read_all(&data, &FeedFilter::default(), limit).len(), (0..SEEDED).filter(|i| live(*i)).count());
+        }
+    }
+
+    #[test]
+    fn filters_by_post_type() {
+        check(FeedFilter { post_type: Some(PostType::OSI), ..Default::default() }, |i| live(i) && i % 2 == 1);
+    }
+
+    #[test]
+    fn filters_by_time_type() {
+        check(FeedFilter { time_type: Some(TimeType::ItemLoan), ..Default::default() }, |i| live(i) && i % 4 == 3);
+    }
+
+    #[test]
+    fn filters_by_owner() {
+        check(FeedFilter { owner: Some("bob".to_string()), ..Default::default() }, |i| live(i) && i % 3 == 1);
+    }
+
+    #[test]
+    fn filters_by_state() {
+        check(FeedFilter { state: Some(PostState::Completed), ..Default::default() }, |i| i % 7 == 6);
+        check(FeedFilter { state: Some(PostState::Expired), ..Default::default() }, |i| i % 7 == 5);
+
+        let draft = FeedFilter { state: Some(PostState::Draft), ..Default::default() };
+        assert!(seed().get_feed(&draft, &FeedPageQuery::default()).is_err());
+    }
+
+    #[test]
+    fn filters_by_tags() {
+        let tags = |tags: &str, tag_mode| FeedFilter { tags: Some(tags.to_string()), tag_mode, ..Default::default() };
+
+        check(tags("tools,garden", TagMode::Any), |i| live(i) && i % 5 < 3);
+        check(tags("Tools", TagMode::Any), |i| live(i) && i % 5 < 2);
+        check(tags("tools,garden", TagMode::All), |i| live(i) && i % 5 == 0);
+        check(tags("tools,unused", TagMode::All), |_| false);
+    }
+
+    #[test]
+    fn combines_filters() {
+        let filter = FeedFilter {
+            post_type: Some(PostType::ISO),
+            owner: Some("alice".to_string()),
+            tags: Some("garden".to_string()),
+            ..Default::default()
+        };
+
+        check(filter, |i| live(i) && i % 2 == 0 && i % 3 == 0 && (i % 5 == 0 || i % 5 == 2));
+    }
+
+    #[test]
+    fn pages_a_users_posts() {
+        let mut data = seed();
+        data.index_feed();
+
+        let mut alice = User::new("alice".to_string(), String::new()).unwrap();
+        for post in data.feed.iter().filter(|post| post.get_owner() == "alice") {
+            alice.add_post(post.uuid.clone());
+        }
+        data.users.insert(alice.uuid.clone(), alice);
+
+        for state in [None, Some(PostState::Completed)] {
+            let mut posts = Vec::new();
+            let mut after = None;
+            loop {
+                let query = FeedPageQuery { after, limit: Some(3), ..Default::default() };
+                let page = data.get_user_posts("alice", PostRole::Owner, state, &query).unwrap();
+                posts.extend(page.items);
+                after = page.next;
+                if after.is_none() {
+                    break;
+                }
+            }
+
+            assert!(posts.windows(2).all(|pair| FeedCursor::of(&pair[0]) > FeedCursor::of(&pair[1])));
+
+            let mut expected: Vec<&str> = data.feed.iter()
+                .filter(|post| post.get_owner() == "alice" && state.map_or(true, |state| post.state == state))
+                .map(|post| post.uuid.as_str())
+                .collect();
+            let mut found = uuids(&posts);
+            expected.sort();
+            found.sort();
+            assert_eq!(found, expected, "{:?}", state);
+        }
+    }
+}
@@ -0,0 +1,219 @@
+use crate::{data::Data, geo::Coordinate, post::Post};
+use ::serde::{Deserialize, Serialize};
+use std::{fs, io::ErrorKind};
+
+/// Lowest score for a `location_string` to be matched to a place
+const MATCH_THRESHOLD: f64 = 0.85;
+/// Lowest score for a place to be suggested while typing
+const SUGGEST_THRESHOLD: f64 = 0.6;
+/// Suggestions returned by autocomplete
+pub const SUGGESTION_LIMIT: usize = 10;
+pub const PLACE_ID_MAX: usize = 64;
+pub const PLACE_NAME_MAX: usize = 100;
+
+/// A named place on campus that posts can be matched to
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Place {
+    /// Stable slug, e.g. `hoch-dining-hall`
+    pub id: String,
+    pub name: String,
+    /// Other ways people write it
+    pub aliases: Vec<String>,
+    pub location: Coordinate,
+}
+
+/// An admin's new or updated place; the id comes from the path
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PlaceEdit {
+    pub name: String,
+    pub aliases: Vec<String>,
+    pub location: Coordinate,
+}
+
+impl Place {
+    pub fn check(&self) -> Result<(), String> {
+        let slug = self.id.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+        if self.id.is_empty() || self.id.len() > PLACE_ID_MAX || !slug {
+            return Err(format!("Place id must be 1 to {} lowercase letters, digits and dashes", PLACE_ID_MAX));
+        }
+
+        for name in self.names() {
+            if normalize(name).is_empty() || name.chars().count() > PLACE_NAME_MAX {
+                return Err(format!("Place names must have a letter or digit and be at most {} characters", PLACE_NAME_MAX));
+            }
+        }
+
+        self.location.check()
+    }
+
+    fn names(&self) -> impl Iterator<Item = &String> {
+        std::iter::once(&self.name).chain(self.aliases.iter())
+    }
+
+    /// How well `query` names this place, from 0 to 1. `query` must be
+    /// normalized.
+    fn score(&self, query: &str) -> f64 {
+        self.names().map(|name| match_score(query, &normalize(name))).fold(0.0, f64::max)
+    }
+
+    /// How well this place fits a partly typed `query`, from 0 to 1.
+    /// `query` must be normalized.
+    fn suggest_score(&self, query: &str) -> f64 {
+        self.names().map(|name| prefix_score(query, &normalize(name))).fold(0.0, f64::max)
+    }
+}
+
+/// Lowercase words with punctuation dropped, so "Hoch  Dining-Hall" and
+/// "hoch dining hall" compare equal
+pub fn normalize(s: &str) -> String {
+    let spaced: String = s.chars()
+        .flat_map(char::to_lowercase)
+        .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+        .collect();
+
+    spaced.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+fn levenshtein(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+
+    for (i, ca) in a.chars().enumerate() {
+        let mut diagonal = row[0];
+        row[0] = i + 1;
+
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = diagonal + usize::from(ca != *cb);
+            diagonal = row[j + 1];
+            row[j + 1] = substitution.min(row[j] + 1).min(diagonal + 1);
+        }
+    }
+
+    row[b.len()]
+}
+
+/// 1 for equal strings, falling towards 0 as more edits are needed
+fn similarity(a: &str, b: &str) -> f64 {
+    let len = a.chars().count().max(b.chars().count());
+    if len == 0 {
+        return 1.0;
+    }
+
+    1.0 - levenshtein(a, b) as f64 / len as f64
+}
+
+/// The whole query against the name, or every word of the name found
+/// (roughly) in a longer query such as "hoch dining hall upstairs"
+fn match_score(query: &str, name: &str) -> f64 {
+    let words: Vec<&str> = query.split(' ').collect();
+
+    let found = name.split(' ')
+        .map(|word| words.iter().map(|x| similarity(word, x)).fold(0.0, f64::max))
+        .sum::<f64>() / name.split(' ').count() as f64;
+
+    similarity(query, name).max(0.9 * found)
+}
+
+fn prefix_score(query: &str, name: &str) -> f64 {
+    if name.starts_with(query) {
+        return 1.0;
+    }
+    if name.split(' ').any(|word| word.starts_with(query)) {
+        return 0.9;
+    }
+
+    // Allow for typos in what's been typed so far
+    let prefix: String = name.chars().take(query.chars().count()).collect();
+    0.8 * similarity(query, &prefix)
+}
+
+/// Read the bundled gazetteer, a JSON array of places. A missing file is
+/// an empty gazetteer.
+pub fn read_gazetteer(path: &str) -> Result<Vec<Place>, String> {
+    match fs::read_to_string(path) {
+        Ok(contents) => serde_json::from_str(&contents).map_err(|e| format!("Failed to parse {}: {}", path, e)),
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(format!("Failed to read {}: {}", path, e)),
+    }
+}
+
+impl Data {
+    /// The place `location_string` most likely names, if any is close enough
+    pub fn match_place(&self, location_string: &str) -> Option<&Place> {
+        let query = normalize(location_string);
+        if query.is_empty() {
+            return None;
+        }
+
+        self.places.values()
+            .map(|place| (place, place.score(&query)))
+            .filter(|(_, score)| *score >= MATCH_THRESHOLD)
+            // Ties go to the lowest id, so the answer doesn't depend on hashing
+            .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.0.id.cmp(&a.0.id)))
+            .map(|(place, _)| place)
+    }
+
+    /// Match `post` to a place from its `location_string`. Coordinates the
+    /// owner didn't give follow the place.
+    pub(crate) fn geocode(&self, post: &mut Post) {
+        let previous = post.place_id.take().and_then(|id| self.places.get(&id));
+        let place = self.match_place(&post.location_string);
+
+        let derived = post.location.is_none() || previous.is_some_and(|previous| post.location == Some(previous.location));
+        if derived {
+            post.location = place.map(|place| place.location);
+        }
+
+        post.place_id = place.map(|place| place.id.clone());
+    }
+
+    /// Places for a partly typed location, best first
+    pub fn suggest_places(&self, query: &str) -> Vec<&Place> {
+        let query = normalize(query);
+        if query.is_empty() {
+            return Vec::new();
+        }
+
+        let mut suggestions: Vec<(&Place, f64)> = self.places.values()
+            .map(|place| (place, place.suggest_score(&query)))
+            .filter(|(_, score)| *score >= SUGGEST_THRESHOLD)
+            .collect();
+
+        suggestions.sort_by(|a, b| b.1.total_cmp(&a.1).then_with(|| a.0.name.cmp(&b.0.name)));
+        suggestions.truncate(SUGGESTION_LIMIT);
+
+        suggestions.into_iter().map(|(place, _)| place).collect()
+    }
+
+    /// Every place, by name
+    pub fn get_places(&self) -> Vec<&Place> {
+        let mut places: Vec<&Place> = self.places.values().collect();
+
+        places.sort_by(|a, b| a.name.cmp(&b.name));
+        places
+    }
+
+    /// Add a place, or replace the one with the same id. Posts already
+    /// matched keep their place id.
+    pub fn put_place(&mut self, place: Place) -> Result<Place, String> {
+        place.check()?;
+
+        self.persist(|s| s.put_place(&place))?;
+        self.places.insert(place.id.clone(), place.clone());
+
+        Ok(place)
+    }
+
+    pub fn delete_place(&mut self, id: &str) -> Result<(), String> {
+        if !self.places.contains_key(id) {
+            return Err("Place not found".to_string());
+        }
+
+        self.persist(|s| s.delete_place(id))?;
+        self.places.remove(id);
+
+        Ok(())
+    }
+}
@@ -0,0 +1,172 @@
+use crate::{data::Data, post::{FeedFilter, Post}};
+use ::serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+
+/// Mean radius of the Earth
+const EARTH_RADIUS_KM: f64 = 6371.0;
+/// Largest radius a nearby search can cover
+pub const MAX_RADIUS_KM: f64 = 100.0;
+/// Geohash length of an index cell, about 4.9 km on a side
+const CELL_PRECISION: usize = 5;
+const GEOHASH_ALPHABET: &[u8] = b"0123456789bcdefghjkmnpqrstuvwxyz";
+
+#[derive(Default, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct Coordinate {
+    pub lat: f64,
+    pub lon: f64,
+}
+
+impl Coordinate {
+    pub fn check(&self) -> Result<(), String> {
+        if !(-90.0..=90.0).contains(&self.lat) || !(-180.0..=180.0).contains(&self.lon) {
+            return Err("Coordinates out of range".to_string());
+        }
+
+        Ok(())
+    }
+
+    /// Great-circle distance
+    pub fn distance_km(&self, other: &Coordinate) -> f64 {
+        let (lat1, lat2) = (self.lat.to_radians(), other.lat.to_radians());
+        let d_lat = lat2 - lat1;
+        let d_lon = (other.lon - self.lon).to_radians();
+
+        let a = (d_lat / 2.0).sin().powi(2) + lat1.cos() * lat2.cos() * (d_lon / 2.0).sin().powi(2);
+        2.0 * EARTH_RADIUS_KM * a.sqrt().asin()
+    }
+
+    pub fn geohash(&self, precision: usize) -> String {
+        let (mut lat_range, mut lon_range) = ((-90.0, 90.0), (-180.0, 180.0));
+        let mut hash = String::with_capacity(precision);
+        let mut even = true;
+        let (mut bits, mut bit) = (0, 0);
+
+        while hash.len() < precision {
+            let (range, value): (&mut (f64, f64), f64) = if even { (&mut lon_range, self.lon) } else { (&mut lat_range, self.lat) };
+            let mid = (range.0 + range.1) / 2.0;
+
+            bits <<= 1;
+            if value >= mid {
+                bits |= 1;
+                range.0 = mid;
+            } else {
+                range.1 = mid;
+            }
+
+            even = !even;
+            bit += 1;
+            if bit == 5 {
+                hash.push(GEOHASH_ALPHABET[bits] as char);
+                bits = 0;
+                bit = 0;
+            }
+        }
+
+        hash
+    }
+}
+
+/// Size of a cell at `CELL_PRECISION`, in degrees of latitude and longitude
+fn cell_size() -> (f64, f64) {
+    let bits = CELL_PRECISION * 5;
+    let lon_bits = (bits + 1) / 2;
+    let lat_bits = bits / 2;
+
+    (180.0 / (1u64 << lat_bits) as f64, 360.0 / (1u64 << lon_bits) as f64)
+}
+
+/// UUIDs of posts with a location, bucketed by geohash cell. Posts are
+/// looked up in the feed, so only their cells are kept here.
+#[derive(Default, Clone)]
+pub struct GeoIndex {
+    cells: HashMap<String, HashSet<String>>,
+    /// Post UUID to its cell
+    posts: HashMap<String, String>,
+}
+
+impl GeoIndex {
+    /// Track `post` at its current location, or drop it if it has none
+    pub fn update(&mut self, post: &Post) {
+        self.remove(&post.uuid);
+
+        if let Some(location) = post.location {
+            let cell = location.geohash(CELL_PRECISION);
+            self.cells.entry(cell.clone()).or_default().insert(post.uuid.clone());
+            self.posts.insert(post.uuid.clone(), cell);
+        }
+    }
+
+    pub fn remove(&mut self, uuid: &str) {
+        if let Some(cell) = self.posts.remove(uuid) {
+            if let Some(posts) = self.cells.get_mut(&cell) {
+                posts.remove(uuid);
+                if posts.is_empty() {
+                    self.cells.remove(&cell);
+                }
+            }
+        }
+    }
+
+    /// UUIDs of the posts in cells that `radius_km` around `center` reaches.
+    /// Some can be further away.
+    pub fn near(&self, center: &Coordinate, radius_km: f64) -> Vec<&str> {
+        let (lat_step, lon_step) = cell_size();
+
+        // Bounding box of the circle, in degrees
+        let d_lat = (radius_km / EARTH_RADIUS_KM).to_degrees();
+        let d_lon = (d_lat / center.lat.to_radians().cos().max(0.01)).min(180.0);
+
+        let mut cells = HashSet::new();
+        let mut lat = (center.lat - d_lat).max(-90.0);
+        while lat <= (center.lat + d_lat).min(90.0) + lat_step {
+            let mut lon = center.lon - d_lon;
+            while lon <= center.lon + d_lon + lon_step {
+                // Wrap around the antimeridian
+                let wrapped = (lon + 540.0).rem_euclid(360.0) - 180.0;
+                cells.insert(Coordinate { lat: lat.min(90.0), lon: wrapped }.geohash(CELL_PRECISION));
+                lon += lon_step;
+            }
+            lat += lat_step;
+        }
+
+        cells.iter()
+            .filter_map(|cell| self.cells.get(cell))
+            .flatten()
+            .map(|uuid| uuid.as_str())
+            .collect()
+    }
+}
+
+impl Data {
+    /// Rebuild `geo_index` from the feed
+    pub fn index_locations(&mut self) {
+        let mut index = GeoIndex::default();
+        for post in self.feed.iter() {
+            index.update(post);
+        }
+        self.geo_index = index;
+    }
+
+    /// Live posts matching `filter` within `radius_km` of `center`, nearest
+    /// first, with their distance
+    pub fn get_nearby(&self, center: &Coordinate, radius_km: f64, filter: &FeedFilter, limit: usize) -> Result<Vec<(Post, f64)>, String> {
+        center.check()?;
+        filter.check()?;
+
+        if !(radius_km > 0.0 && radius_km <= MAX_RADIUS_KM) {
+            return Err(format!("radius_km must be more than 0 and at most {}", MAX_RADIUS_KM));
+        }
+
+        let mut nearby: Vec<(&Post, f64)> = self.geo_index.near(center, radius_km)
+            .into_iter()
+            .filter_map(|uuid| self.indexed_post(uuid))
+            .filter_map(|post| Some((post, center.distance_km(&post.location?))))
+            .filter(|(post, distance)| *distance <= radius_km && filter.shows(post))
+            .collect();
+
+        nearby.sort_by(|a, b| a.1.total_cmp(&b.1));
+        nearby.truncate(limit);
+
+        Ok(nearby.into_iter().map(|(post, distance)| (post.clone(), distance)).collect())
+    }
+}
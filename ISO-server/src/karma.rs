@@ -0,0 +1,194 @@
+use crate::{data::{now, Data}, post::{Post, PostState, PostType}, user::User};
+use ::serde::{Deserialize, Serialize};
+
+/// Why karma moved
+#[derive(Default, Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub enum KarmaReason {
+    /// Karma a user already had before the ledger existed
+    #[default] OpeningBalance,
+    PostCompleted,
+}
+
+/// One movement of karma between two users.
+///
+/// The ledger is append only; a user's karma is the sum of everything paid
+/// to them minus everything they paid.
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct KarmaEntry {
+    pub id: String,
+    pub time: u64,
+    pub reason: KarmaReason,
+    pub post_uuid: Option<String>,
+    /// Who paid, if anyone
+    pub from: Option<String>,
+    /// Who was paid, if anyone
+    pub to: Option<String>,
+    pub amount: i32,
+}
+
+impl KarmaEntry {
+    pub fn new(reason: KarmaReason, post_uuid: Option<String>, from: Option<String>, to: Option<String>, amount: i32) -> KarmaEntry {
+        KarmaEntry {
+            id: uuid::Uuid::new_v4().to_string(),
+            time: now(),
+            reason,
+            post_uuid,
+            from,
+            to,
+            amount,
+        }
+    }
+
+    /// How this entry changes `uuid`'s karma
+    pub fn delta_for(&self, uuid: &str) -> i32 {
+        let mut delta = 0;
+        if self.to.as_deref() == Some(uuid) {
+            delta += self.amount;
+        }
+        if self.from.as_deref() == Some(uuid) {
+            delta -= self.amount;
+        }
+        delta
+    }
+
+    pub fn involves(&self, uuid: &str) -> bool {
+        self.from.as_deref() == Some(uuid) || self.to.as_deref() == Some(uuid)
+    }
+}
+
+/// Who pays whom, and how much, when `post` completes.
+///
+/// On an ISO post the owner asked for help, so the owner pays the acceptor.
+/// On an OSI post the owner offered something, so the acceptor pays the
+/// owner.
+pub fn transfer_for(post: &Post) -> Option<(String, String, i32)> {
+    let acceptor = post.get_acceptor()?;
+    let amount = post.get_karma_diff().abs();
+
+    match post.iso_or_osi {
+        PostType::ISO => Some((post.get_owner(), acceptor, amount)),
+        PostType::OSI => Some((acceptor, post.get_owner(), amount)),
+    }
+}
+
+impl Data {
+    /// Recompute every user's karma from the ledger
+    pub fn index_karma(&mut self) {
+        for user in self.users.values_mut() {
+            user.set_karma(0);
+        }
+
+        for entry in self.karma_ledger.iter() {
+            for uuid in [&entry.from, &entry.to].into_iter().flatten() {
+                if let Some(user) = self.users.get_mut(uuid) {
+                    let karma = user.get_karma() + entry.delta_for(uuid);
+                    user.set_karma(karma);
+                }
+            }
+        }
+    }
+
+    /// Karma `payer` has promised to accepted posts that haven't completed
+    pub fn reserved_karma(&self, payer: &str) -> i32 {
+        self.feed.iter()
+            .filter(|post| post.state == PostState::Accepted)
+            .filter_map(transfer_for)
+            .filter(|(from, _, _)| from == payer)
+            .map(|(_, _, amount)| amount)
+            .sum()
+    }
+
+    /// Make sure `payer` can pay `amount` on top of what they have already
+    /// promised, without going below `floor`
+    pub fn check_karma_floor(&self, payer: &str, amount: i32, floor: i32) -> Result<(), String> {
+        let user = self.users.get(payer).ok_or("User not found".to_string())?;
+        let left = user.get_karma() - self.reserved_karma(payer) - amount;
+
+        if left < floor {
+            return Err(format!("Not enough karma: paying {} on top of posts already accepted would leave {}, below the floor of {}", amount, left, floor));
+        }
+
+        Ok(())
+    }
+
+    /// Build the entry paying out a completed post, updating both users' karma.
+    /// The payer could afford it when the claim was approved, and it has
+    /// been reserved since, so this never fails on the floor.
+    pub(crate) fn settle_post(&self, post: &Post) -> Result<(KarmaEntry, Vec<User>), String> {
+        let (payer_uuid, payee_uuid, amount) = transfer_for(post).ok_or("Post has no acceptor".to_string())?;
+
+        let mut payer = self.users.get(&payer_uuid).cloned().ok_or("User not found".to_string())?;
+        let mut payee = self.users.get(&payee_uuid).cloned().ok_or("User not found".to_string())?;
+
+        let entry = KarmaEntry::new(KarmaReason::PostCompleted, Some(post.uuid.clone()), Some(payer_uuid), Some(payee_uuid), amount);
+
+        payer.set_karma(payer.get_karma() - amount);
+        payee.set_karma(payee.get_karma() + amount);
+        payee.notify(post.uuid.clone(), format!("You received {} karma for \"{}\"", amount, post.title));
+
+        Ok((entry, vec![payer, payee]))
+    }
+
+    /// Every ledger entry involving `uuid`, newest first
+    pub fn karma_history(&self, uuid: &str) -> Result<Vec<KarmaEntry>, String> {
+        if !self.users.contains_key(uuid) {
+            return Err("User not found".to_string());
+        }
+
+        Ok(self.karma_ledger.iter().rev().filter(|entry| entry.involves(uuid)).cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::TimeType;
+
+    const FLOOR: i32 = -2;
+
+    /// Three published ISO posts by `owner`, each paying 1 karma
+    fn seed() -> (Data, Vec<String>) {
+        let mut data = Data::default();
+        for uuid in ["owner", "helper"] {
+            data.users.insert(uuid.to_string(), User::new(uuid.to_string(), String::new()).unwrap());
+        }
+
+        let mut uuids = Vec::new();
+        for i in 0..3 {
+            let mut post = Post::new(format!("Post {}", i), PostType::ISO, "owner".to_string(), TimeType::ServiceNow, vec![], String::new());
+            post.publish("owner", 3600).unwrap();
+            uuids.push(post.uuid.clone());
+            data.feed.push(post);
+        }
+        data.index_feed();
+
+        for uuid in uuids.iter() {
+            data.claim_post(uuid.clone(), "helper").unwrap();
+        }
+
+        (data, uuids)
+    }
+
+    #[test]
+    fn approvals_reserve_karma_until_settled() {
+        let (mut data, uuids) = seed();
+
+        data.approve_claim(&uuids[0], "owner", "helper", FLOOR).unwrap();
+        data.approve_claim(&uuids[1], "owner", "helper", FLOOR).unwrap();
+        assert_eq!(data.reserved_karma("owner"), 2);
+
+        // Nothing is paid yet, but the first two already take it to the floor
+        assert!(data.approve_claim(&uuids[2], "owner", "helper", FLOOR).is_err());
+
+        for uuid in uuids[..2].iter() {
+            data.complete_post(uuid, "helper").unwrap();
+            let post = data.complete_post(uuid, "owner").unwrap();
+            assert_eq!(post.state, PostState::Completed);
+        }
+
+        assert_eq!(data.reserved_karma("owner"), 0);
+        assert_eq!(data.users["owner"].get_karma(), -2);
+        assert_eq!(data.users["helper"].get_karma(), 2);
+    }
+}
@@ -5,7 +5,6 @@ use std::io::Read;
 use std::io::Write;
 use std::process::exit;
 use std::sync::Arc;
-use std::thread;
 
 use actix_cors::*;
 use actix_web::rt::spawn;
@@ -26,21 +25,40 @@ use std::time::Duration;
 use tokio::sync::Mutex;
 use tokio::time;
 
+mod auth;
 mod data;
+mod events;
+mod feed;
+mod gazetteer;
+mod geo;
+mod karma;
+mod message;
+mod migrate;
 mod post;
 mod user;
 mod routes;
+mod search;
+mod session;
+mod verification;
+mod view;
+mod snapshot;
+mod storage;
+mod tags;
+mod wal;
 
 use data::*;
+use events::SHUTDOWN;
 use post::*;
 use user::*;
 use routes::*;
+use verification::*;
 
 include!(concat!(env!("OUT_DIR"), "/generated.rs"));
 
 lazy_static! {
     pub static ref MEMORY_DATABASE: Arc<Mutex<Data>> = Arc::new(Mutex::new(Data::default()));
     pub static ref CONFIG: Arc<Mutex<Config>> = Arc::new(Mutex::new(Config::default()));
+    pub static ref VERIFIER: Arc<Mutex<Option<Arc<dyn VerificationProvider>>>> = Arc::new(Mutex::new(None));
 }
 
 // Debug vs release address
@@ -48,6 +66,8 @@ const ADDRESS: &str = "127.0.0.1:8080";
 const ADDRESS_HTTPS: &str = "0.0.0.0:443";
 
 const DB_NAME: &str = "db.json";
+const SQLITE_DB_NAME: &str = "db.sqlite";
+const GAZETTEER_FILE: &str = "gazetteer.json";
 const LOGGER_STR: &str = "\nMAKE Log @ %t\nIP: %a (%{r}a)\nRequest: \"%r\"\nAgent: \"%{Referer}i\" \"%{User-Agent}i\"\nResponse: STATUS %s for %b bytes in %D ms";
 const VERSION_STRING: &str = env!("CARGO_PKG_VERSION");
 
@@ -55,12 +75,23 @@ fn main() {
     std::env::set_var("RUST_LOG", "info, actix_web=trace");
     env_logger::init();
 
-    ctrlc::set_handler(move || {
-        info!("Exiting...");
-        thread::sleep(Duration::from_secs(2));
+    let args: Vec<String> = std::env::args().collect();
+
+    if args.iter().any(|arg| arg == "--migrate") {
+        let dry_run = args.iter().any(|arg| arg == "--dry-run");
+        let config = load_config();
+        let result = if dry_run {
+            migrate::preview_migrations(&config)
+        } else {
+            open_storage(&config).and_then(|storage| migrate::run_migrations(storage, &config))
+        };
+
+        if let Err(e) = result {
+            error!("Migration failed: {}", e);
+            exit(1);
+        }
         exit(0);
-    })
-    .expect("Error setting Ctrl-C handler");
+    }
 
     let _ = actix_web::rt::System::with_tokio_rt(|| {
         tokio::runtime::Builder::new_multi_thread()
@@ -73,6 +104,23 @@ fn main() {
     .block_on(async_main());
 }
 
+fn load_config() -> Config {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .open("config.toml")
+        .expect("Failed to open config.toml");
+    let mut data = String::new();
+    file.read_to_string(&mut data).expect("Failed to read config.toml");
+
+    let config: Config = toml::from_str(&data).expect("Failed to parse config.toml");
+
+    // A zero interval would panic the sweeper's timer
+    if config.expiry_sweep_interval == 0 {
+        panic!("expiry_sweep_interval in config.toml must be at least 1");
+    }
+
+    config
+}
 
 async fn async_main() -> std::io::Result<()> {
     // Print startup text
@@ -81,21 +129,25 @@ async fn async_main() -> std::io::Result<()> {
     println!("Version {}", VERSION_STRING);
     println!("██████████████████████████████████████████████████████████████");
 
-    let mut file = OpenOptions::new()
-        .read(true)
-        .open("config.toml")
-        .expect("Failed to open config.toml");
-    let mut data = String::new();
-    file.read_to_string(&mut data)?;
+    let data = load_config();
+    let shutdown_timeout = data.shutdown_timeout;
+    let expiry_sweep_interval = data.expiry_sweep_interval;
 
-    let data: Config = toml::from_str(&data).expect("Failed to parse api_keys.toml");
+    let storage = open_storage(&data).expect("Failed to open storage backend");
+    info!("Using {:?} storage backend", data.storage_backend);
+
+    let mut verifier = VERIFIER.lock().await;
+    *verifier = Some(open_verification_provider(&data));
+    drop(verifier);
+    info!("Using {:?} verification provider", data.verification_provider);
 
     let mut config = CONFIG.lock().await;
     *config = data;
     drop(config);
 
     // Load all databases
-    let data = load_database().unwrap();
+    let data = load_database(storage, &*CONFIG.lock().await).expect("Failed to load database");
+
     let mut lock = MEMORY_DATABASE.lock().await;
     *lock = data;
     drop(lock);
@@ -108,6 +160,17 @@ async fn async_main() -> std::io::Result<()> {
         }
     });
 
+    spawn(async move {
+        let mut interval = time::interval(Duration::from_secs(expiry_sweep_interval));
+        loop {
+            interval.tick().await;
+            let expired = db_mut().await.expire_posts();
+            if !expired.is_empty() {
+                info!("Expired {} posts", expired.len());
+            }
+        }
+    });
+
     info!("Database loaded.");
 
     let builder;
@@ -144,18 +207,103 @@ async fn async_main() -> std::io::Result<()> {
             .wrap(actix_web::middleware::Compress::default())
             .wrap(cors)
             .service(get_post_page)
+            .service(get_feed)
+            .service(get_nearby_posts)
+            .service(search_posts)
+            .service(stream_posts)
             .service(get_user_info)
+            .service(get_my_posts)
             .service(new_post)
             .service(start_verification)
             .service(check_verification)
             .service(claim_post)
+            .service(withdraw_claim)
+            .service(approve_claim)
             .service(get_individual_post)
+            .service(publish_post)
+            .service(cancel_post)
+            .service(complete_post)
+            .service(reopen_post)
+            .service(renew_post)
+            .service(edit_post)
+            .service(delete_post)
+            .service(get_sessions)
+            .service(revoke_session)
+            .service(logout_all)
+            .service(get_karma_history)
+            .service(edit_profile)
+            .service(set_location)
+            .service(events_socket)
+            .service(send_message)
+            .service(get_conversations)
+            .service(mark_read)
+            // After the other `/api/v1/messages/...` routes, so it doesn't shadow them
+            .service(get_messages)
+            .service(get_places)
+            .service(autocomplete_places)
+            .service(put_place)
+            .service(delete_place)
+            .service(get_admin_user)
+            .service(get_tags)
+            .service(get_trending_tags)
+            .service(put_tag)
+            .service(delete_tag)
+            // After every other `/api/v1/users/...` route, so it doesn't shadow them
+            .service(get_user_profile)
             .service(ResourceFiles::new("/", generate()))
     });
 
-    if builder.is_some() {
-        server.bind_openssl(ADDRESS, builder.unwrap())?.run().await
+    // Signals are handled below so that the database is saved after the
+    // last request has finished
+    let server = server
+        .disable_signals()
+        .shutdown_timeout(shutdown_timeout);
+
+    let server = if builder.is_some() {
+        server.bind_openssl(ADDRESS, builder.unwrap())?.run()
     } else {
-        server.bind(ADDRESS)?.run().await
+        server.bind(ADDRESS)?.run()
+    };
+
+    let handle = server.handle();
+
+    spawn(async move {
+        let signal = wait_for_shutdown_signal().await;
+        info!("Received {}, no longer accepting connections", signal);
+        info!("Closing {} feed streams and event sockets", SHUTDOWN.receiver_count());
+        SHUTDOWN.send_replace(true);
+        info!("Draining in-flight requests (up to {} seconds)...", shutdown_timeout);
+        handle.stop(true).await;
+    });
+
+    let result = server.await;
+    info!("All workers stopped.");
+
+    if let Err(e) = save_database().await {
+        error!("Failed to save database on shutdown: {}", e);
+    }
+
+    info!("Shutdown complete.");
+    result
+}
+
+/// Resolves on SIGINT or SIGTERM with the name of the signal
+async fn wait_for_shutdown_signal() -> &'static str {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut sigterm = signal(SignalKind::terminate()).expect("Error setting SIGTERM handler");
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => "SIGINT",
+            _ = sigterm.recv() => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        tokio::signal::ctrl_c().await.expect("Error setting Ctrl-C handler");
+        "Ctrl-C"
     }
 }
@@ -0,0 +1,306 @@
+use crate::{data::{now, Data}, events::EventKind, feed::{from_hex, paginate, to_hex, FeedPageQuery, Page, PageCursor}, post::{Post, PostState}};
+use ::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Messages returned per page of history unless asked for another size
+pub const MESSAGE_PAGE_SIZE: usize = 50;
+/// Longest allowed message, in characters
+pub const MESSAGE_MAX: usize = 2000;
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Message {
+    pub id: String,
+    pub post_uuid: String,
+    pub sender: String,
+    pub body: String,
+    pub time_sent: u64,
+    /// The acceptor this message's thread is with. A reopened post starts a
+    /// new thread with its next acceptor.
+    pub acceptor: String,
+}
+
+/// A position in a conversation, which is paged newest first by
+/// `(time_sent, id)` like the feed
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct MessageCursor {
+    pub time_sent: u64,
+    pub id: String,
+}
+
+impl MessageCursor {
+    pub fn of(message: &Message) -> MessageCursor {
+        MessageCursor { time_sent: message.time_sent, id: message.id.clone() }
+    }
+}
+
+impl PageCursor for MessageCursor {
+    fn encode(&self) -> String {
+        to_hex(&format!("{}:{}", self.time_sent, self.id))
+    }
+
+    fn decode(cursor: &str) -> Result<MessageCursor, String> {
+        let invalid = || "Invalid cursor".to_string();
+
+        let decoded = from_hex(cursor).ok_or_else(invalid)?;
+        let (time_sent, id) = decoded.split_once(':').ok_or_else(invalid)?;
+
+        Ok(MessageCursor {
+            time_sent: time_sent.parse().map_err(|_| invalid())?,
+            id: id.to_string(),
+        })
+    }
+}
+
+/// The messages about one post, in a thread per acceptor it has had
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Conversation {
+    /// Oldest first, across every thread
+    pub messages: Vec<Message>,
+    /// How many messages each user has read, by user UUID
+    pub read: HashMap<String, usize>,
+}
+
+impl Conversation {
+    /// The messages with `acceptor`, oldest first
+    pub fn thread<'a>(&'a self, acceptor: &'a str) -> impl Iterator<Item = &'a Message> + 'a {
+        self.messages.iter().filter(move |message| message.acceptor == acceptor)
+    }
+
+    /// Everyone the owner has had a thread with, oldest first
+    pub fn acceptors(&self) -> Vec<&str> {
+        let mut acceptors: Vec<&str> = Vec::new();
+        for message in self.messages.iter() {
+            if !acceptors.contains(&message.acceptor.as_str()) {
+                acceptors.push(&message.acceptor);
+            }
+        }
+        acceptors
+    }
+
+    pub fn unread(&self, user_uuid: &str, acceptor: &str) -> usize {
+        let read = self.read.get(user_uuid).copied().unwrap_or(0);
+
+        self.messages.iter().skip(read).filter(|message| message.acceptor == acceptor && message.sender != user_uuid).count()
+    }
+
+    /// Apply a message, ignoring it if it's already there
+    pub fn add_message(&mut self, message: Message) {
+        if !self.messages.iter().any(|x| x.id == message.id) {
+            self.messages.push(message);
+        }
+    }
+}
+
+/// Only the owner and acceptor of a post can use its conversation
+fn check_participant(post: &Post, user_uuid: &str) -> Result<(), String> {
+    if post.get_owner() != user_uuid && post.get_acceptor().as_deref() != Some(user_uuid) {
+        return Err("Only the owner and acceptor of a post can message about it".to_string());
+    }
+
+    Ok(())
+}
+
+/// The acceptor of the thread `user_uuid` is asking for. Owners get the one
+/// `with` names, or else the one with the current acceptor. Acceptors only
+/// ever get their own, even once the post has moved on.
+fn thread_acceptor(post: &Post, conversation: Option<&Conversation>, user_uuid: &str, with: Option<&str>) -> Result<String, String> {
+    if post.get_owner() == user_uuid {
+        return with.map(str::to_string).or(post.get_acceptor()).ok_or("The post has no acceptor to message".to_string());
+    }
+
+    let past = conversation.is_some_and(|conversation| conversation.thread(user_uuid).next().is_some());
+    if post.get_acceptor().as_deref() == Some(user_uuid) || past {
+        return Ok(user_uuid.to_string());
+    }
+
+    Err("Only the owner and acceptor of a post can message about it".to_string())
+}
+
+impl Data {
+    pub fn send_message(&mut self, post_uuid: &str, user_uuid: &str, body: String) -> Result<Message, String> {
+        let post = self.get_post_by_uuid(post_uuid)?;
+        check_participant(&post, user_uuid)?;
+
+        if post.state != PostState::Accepted {
+            return Err(format!("Cannot message about a post that is {:?}", post.state));
+        }
+
+        let body = body.trim().to_string();
+        if body.is_empty() {
+            return Err("Message is empty".to_string());
+        }
+        if body.chars().count() > MESSAGE_MAX {
+            return Err(format!("Message must be at most {} characters", MESSAGE_MAX));
+        }
+
+        let message = Message {
+            id: uuid::Uuid::new_v4().to_string(),
+            post_uuid: post_uuid.to_string(),
+            sender: user_uuid.to_string(),
+            body,
+            time_sent: now(),
+            acceptor: post.get_acceptor().unwrap_or_default(),
+        };
+
+        self.persist(|s| s.add_message(&message))?;
+        self.conversations.entry(post_uuid.to_string()).or_default().add_message(message.clone());
+
+        let recipient = if post.get_owner() == user_uuid { post.get_acceptor() } else { Some(post.get_owner()) };
+        if let Some(recipient) = recipient {
+            self.emit(&recipient, EventKind::MessageReceived { message: message.clone() });
+        }
+
+        Ok(message)
+    }
+
+    /// Every thread `user_uuid` is in, with the acceptor it is with, most
+    /// recent first
+    pub fn get_conversations(&self, user_uuid: &str) -> Vec<(Post, String, &Conversation)> {
+        let mut conversations: Vec<(Post, String, &Conversation)> = Vec::new();
+
+        for (uuid, conversation) in self.conversations.iter() {
+            let post = match self.indexed_post(uuid) {
+                Some(post) => post,
+                None => continue,
+            };
+
+            if post.get_owner() == user_uuid {
+                for acceptor in conversation.acceptors() {
+                    conversations.push((post.clone(), acceptor.to_string(), conversation));
+                }
+            } else if conversation.thread(user_uuid).next().is_some() {
+                conversations.push((post.clone(), user_uuid.to_string(), conversation));
+            }
+        }
+
+        conversations.sort_by_key(|(_, acceptor, conversation)| {
+            std::cmp::Reverse(conversation.thread(acceptor).last().map_or(0, |message| message.time_sent))
+        });
+
+        conversations
+    }
+
+    /// A page of one thread of a conversation, newest first. `with` picks
+    /// the owner's thread, see `thread_acceptor`.
+    pub fn get_messages(&self, post_uuid: &str, user_uuid: &str, with: Option<&str>, query: &FeedPageQuery) -> Result<Page<Message>, String> {
+        let post = self.get_post_by_uuid(post_uuid)?;
+        let conversation = self.conversations.get(post_uuid);
+        let acceptor = thread_acceptor(&post, conversation, user_uuid, with)?;
+
+        let mut messages: Vec<(MessageCursor, &Message)> = conversation
+            .map(|conversation| conversation.thread(&acceptor).map(|message| (MessageCursor::of(message), message)).collect())
+            .unwrap_or_default();
+
+        messages.sort_by(|a, b| b.0.cmp(&a.0));
+
+        paginate(&messages, query, MESSAGE_PAGE_SIZE)
+    }
+
+    /// Mark a thread read up to and including `message_id`, or entirely if
+    /// `None`. Returns how many are still unread in it.
+    pub fn mark_read(&mut self, post_uuid: &str, user_uuid: &str, with: Option<&str>, message_id: Option<&str>) -> Result<usize, String> {
+        let post = self.get_post_by_uuid(post_uuid)?;
+        let mut conversation = self.conversations.get(post_uuid).cloned().ok_or("No messages yet".to_string())?;
+        let acceptor = thread_acceptor(&post, Some(&conversation), user_uuid, with)?;
+
+        // Read markers count across threads, which only follow each other
+        let in_thread = |message: &Message| message.acceptor == acceptor && message_id.map_or(true, |id| message.id == id);
+        let count = match conversation.messages.iter().rposition(in_thread) {
+            Some(position) => position + 1,
+            None if message_id.is_some() => return Err("Message not found".to_string()),
+            None => 0,
+        };
+
+        // Never move the read marker backwards
+        let count = count.max(conversation.read.get(user_uuid).copied().unwrap_or(0));
+
+        self.persist(|s| s.mark_read(post_uuid, user_uuid, count))?;
+        conversation.read.insert(user_uuid.to_string(), count);
+
+        let unread = conversation.unread(user_uuid, &acceptor);
+        self.conversations.insert(post_uuid.to_string(), conversation);
+
+        Ok(unread)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{post::{PostLifetimes, PostType, TimeType}, user::User};
+
+    /// A published post and the users who will take turns accepting it
+    fn seed() -> (Data, String) {
+        let mut data = Data::default();
+        for uuid in ["owner", "first", "second"] {
+            data.users.insert(uuid.to_string(), User::new(uuid.to_string(), String::new()).unwrap());
+        }
+
+        let mut post = Post::new("Ladder".to_string(), PostType::OSI, "owner".to_string(), TimeType::ItemLoan, vec![], String::new());
+        post.publish("owner", 3600).unwrap();
+        let uuid = post.uuid.clone();
+
+        data.feed.push(post);
+        data.index_feed();
+        (data, uuid)
+    }
+
+    fn accept(data: &mut Data, uuid: &str, acceptor: &str) {
+        data.claim_post(uuid.to_string(), acceptor).unwrap();
+        data.approve_claim(uuid, "owner", acceptor, -10).unwrap();
+    }
+
+    /// Bodies of a thread, sorted, as messages sent within a second page in
+    /// UUID order
+    fn bodies(data: &Data, uuid: &str, user_uuid: &str, with: Option<&str>) -> Vec<String> {
+        let page = data.get_messages(uuid, user_uuid, with, &FeedPageQuery::default()).unwrap();
+
+        let mut bodies: Vec<String> = page.items.into_iter().map(|message| message.body).collect();
+        bodies.sort();
+        bodies
+    }
+
+    #[test]
+    fn reopened_post_starts_a_new_thread() {
+        let (mut data, uuid) = seed();
+
+        accept(&mut data, &uuid, "first");
+        data.send_message(&uuid, "first", "first to owner".to_string()).unwrap();
+        data.send_message(&uuid, "owner", "owner to first".to_string()).unwrap();
+
+        data.reopen_post(&uuid, "owner", &PostLifetimes::default()).unwrap();
+        accept(&mut data, &uuid, "second");
+
+        assert!(bodies(&data, &uuid, "second", None).is_empty());
+        data.send_message(&uuid, "second", "second to owner".to_string()).unwrap();
+
+        assert_eq!(bodies(&data, &uuid, "second", None), vec!["second to owner"]);
+        assert_eq!(bodies(&data, &uuid, "owner", None), vec!["second to owner"]);
+        assert_eq!(bodies(&data, &uuid, "owner", Some("first")), vec!["first to owner", "owner to first"]);
+
+        // The old acceptor can still read their thread, but nothing else
+        assert_eq!(bodies(&data, &uuid, "first", None), vec!["first to owner", "owner to first"]);
+        assert_eq!(bodies(&data, &uuid, "first", Some("second")), vec!["first to owner", "owner to first"]);
+        assert!(data.send_message(&uuid, "first", "Still there?".to_string()).is_err());
+        assert!(data.get_messages(&uuid, "stranger", None, &FeedPageQuery::default()).is_err());
+
+        let threads = |user_uuid: &str| {
+            let mut threads: Vec<String> = data.get_conversations(user_uuid).into_iter().map(|(_, acceptor, _)| acceptor).collect();
+            threads.sort();
+            threads
+        };
+        assert_eq!(threads("owner"), vec!["first", "second"]);
+        assert_eq!(threads("first"), vec!["first"]);
+        assert_eq!(threads("second"), vec!["second"]);
+
+        let conversation = &data.conversations[&uuid];
+        assert_eq!(conversation.unread("first", "first"), 1);
+        assert_eq!(conversation.unread("second", "second"), 0);
+        assert_eq!(conversation.unread("owner", "second"), 1);
+
+        assert_eq!(data.mark_read(&uuid, "owner", None, None).unwrap(), 0);
+        assert_eq!(data.mark_read(&uuid, "first", None, None).unwrap(), 0);
+    }
+}
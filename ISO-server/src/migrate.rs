@@ -0,0 +1,486 @@
+use crate::{data::{preview_document, Config, Data}, gazetteer::read_gazetteer, karma::{KarmaEntry, KarmaReason}, session::{hash_token, Session}, storage::Storage, tags::normalize_tag};
+use log::*;
+use serde_json::{json, Value};
+use std::sync::Arc;
+
+/// Version of the persisted `Data` document this build reads and writes
+pub const SCHEMA_VERSION: u32 = 10;
+
+/// One step in upgrading a persisted document
+pub struct Migration {
+    /// Version this migration upgrades from, to `from + 1`
+    pub from: u32,
+    pub description: &'static str,
+    /// Upgrade the document in place, returning a line per change made
+    pub run: fn(&mut Value, &Config) -> Result<Vec<String>, String>,
+}
+
+/// Every migration, in order. Append only: never edit one that has shipped.
+const MIGRATIONS: &[Migration] = &[
+    Migration {
+        from: 0,
+        description: "Stamp unversioned documents with a schema version",
+        run: v0_stamp_version,
+    },
+    Migration {
+        from: 1,
+        description: "Replace plaintext user tokens with hashed sessions",
+        run: v1_hash_tokens,
+    },
+    Migration {
+        from: 2,
+        description: "Move drafts that were already on the feed to Posted",
+        run: v2_publish_drafts,
+    },
+    Migration {
+        from: 3,
+        description: "Open the karma ledger with each user's existing karma",
+        run: v3_open_karma_ledger,
+    },
+    Migration {
+        from: 4,
+        description: "Split each user's posts into authored and claimed",
+        run: v4_split_claimed_posts,
+    },
+    Migration {
+        from: 5,
+        description: "Backfill joined dates from each user's oldest session or post",
+        run: v5_backfill_joined,
+    },
+    Migration {
+        from: 6,
+        description: "Store user locations as optional coordinates",
+        run: v6_user_coordinates,
+    },
+    Migration {
+        from: 7,
+        description: "Normalize post tags",
+        run: v7_normalize_tags,
+    },
+    Migration {
+        from: 8,
+        description: "Split conversations into a thread per acceptor",
+        run: v8_conversation_threads,
+    },
+    Migration {
+        from: 9,
+        description: "Seed the gazetteer from gazetteer_file",
+        run: v9_seed_places,
+    },
+];
+
+fn v0_stamp_version(_document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    // The original format is version 0, so there is nothing to rewrite
+    Ok(Vec::new())
+}
+
+fn v1_hash_tokens(document: &mut Value, config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let users = match document.get_mut("users").and_then(|users| users.as_object_mut()) {
+        Some(users) => users,
+        None => return Ok(changes),
+    };
+
+    for (uuid, user) in users.iter_mut() {
+        let user = user.as_object_mut().ok_or(format!("User {} is not an object", uuid))?;
+        let token = user.remove("token");
+
+        let mut sessions = Vec::new();
+
+        // Keep already signed-in clients working with their old token
+        if let Some(token) = token.as_ref().and_then(|token| token.as_str()).filter(|token| !token.is_empty()) {
+            let (mut session, _) = Session::new("Legacy token".to_string(), config.session_lifetime);
+            session.token_hash = hash_token(token);
+            sessions.push(serde_json::to_value(session).map_err(|e| e.to_string())?);
+            changes.push(format!("user {}: token moved to a hashed session", uuid));
+        }
+
+        user.insert("sessions".to_string(), Value::Array(sessions));
+    }
+
+    Ok(changes)
+}
+
+fn v2_publish_drafts(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let feed = match document.get_mut("feed").and_then(|feed| feed.as_array_mut()) {
+        Some(feed) => feed,
+        None => return Ok(changes),
+    };
+
+    // Nothing used to publish posts, so every unclaimed "Draft" was live
+    for post in feed.iter_mut() {
+        if post["state"] != "Draft" {
+            continue;
+        }
+
+        let state = if post["user_acceptor"].is_string() { "Accepted" } else { "Posted" };
+        post["state"] = Value::from(state);
+        changes.push(format!("post {}: Draft -> {}", post["uuid"].as_str().unwrap_or_default(), state));
+    }
+
+    Ok(changes)
+}
+
+fn v3_open_karma_ledger(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+    let mut ledger = Vec::new();
+
+    if let Some(users) = document.get("users").and_then(|users| users.as_object()) {
+        for (uuid, user) in users.iter() {
+            let karma = user["karma"].as_i64().unwrap_or(0) as i32;
+            if karma == 0 {
+                continue;
+            }
+
+            // Negative balances are recorded as paid out to nobody
+            let (from, to) = if karma > 0 { (None, Some(uuid.clone())) } else { (Some(uuid.clone()), None) };
+            let entry = KarmaEntry::new(KarmaReason::OpeningBalance, None, from, to, karma.abs());
+            ledger.push(serde_json::to_value(entry).map_err(|e| e.to_string())?);
+            changes.push(format!("user {}: opening balance of {}", uuid, karma));
+        }
+    }
+
+    document["karma_ledger"] = Value::Array(ledger);
+
+    Ok(changes)
+}
+
+fn v4_split_claimed_posts(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    // The feed is newest first, user post lists are oldest first
+    let feed: Vec<Value> = document["feed"].as_array().cloned().unwrap_or_default().into_iter().rev().collect();
+
+    let users = match document.get_mut("users").and_then(|users| users.as_object_mut()) {
+        Some(users) => users,
+        None => return Ok(changes),
+    };
+
+    for (uuid, user) in users.iter_mut() {
+        let user = user.as_object_mut().ok_or(format!("User {} is not an object", uuid))?;
+
+        let posts_with = |field: &str| -> Vec<Value> {
+            feed.iter().filter(|post| post[field].as_str() == Some(uuid.as_str())).map(|post| post["uuid"].clone()).collect()
+        };
+
+        let posts = posts_with("user_owner");
+        let claimed_posts = posts_with("user_acceptor");
+
+        let before = user.get("posts").and_then(|posts| posts.as_array()).map_or(0, |posts| posts.len());
+        if before != posts.len() || !claimed_posts.is_empty() {
+            changes.push(format!("user {}: {} posts -> {} authored, {} claimed", uuid, before, posts.len(), claimed_posts.len()));
+        }
+
+        user.insert("posts".to_string(), Value::Array(posts));
+        user.insert("claimed_posts".to_string(), Value::Array(claimed_posts));
+    }
+
+    Ok(changes)
+}
+
+fn v5_backfill_joined(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let feed = document["feed"].as_array().cloned().unwrap_or_default();
+
+    let users = match document.get_mut("users").and_then(|users| users.as_object_mut()) {
+        Some(users) => users,
+        None => return Ok(changes),
+    };
+
+    for (uuid, user) in users.iter_mut() {
+        let user = user.as_object_mut().ok_or(format!("User {} is not an object", uuid))?;
+
+        let sessions = user.get("sessions").and_then(|sessions| sessions.as_array()).cloned().unwrap_or_default();
+        let session_times = sessions.iter().filter_map(|session| session["time_created"].as_u64());
+        let post_times = feed.iter()
+            .filter(|post| post["user_owner"].as_str() == Some(uuid.as_str()))
+            .filter_map(|post| post["time_posted"].as_u64());
+
+        let time_joined = session_times.chain(post_times).filter(|time| *time > 0).min().unwrap_or(0);
+
+        user.insert("time_joined".to_string(), Value::from(time_joined));
+        if time_joined > 0 {
+            changes.push(format!("user {}: joined at {}", uuid, time_joined));
+        }
+    }
+
+    Ok(changes)
+}
+
+fn v6_user_coordinates(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let users = match document.get_mut("users").and_then(|users| users.as_object_mut()) {
+        Some(users) => users,
+        None => return Ok(changes),
+    };
+
+    for (uuid, user) in users.iter_mut() {
+        let user = user.as_object_mut().ok_or(format!("User {} is not an object", uuid))?;
+
+        let pair = user.get("current_location").and_then(|location| location.as_array()).cloned().unwrap_or_default();
+        let lat = pair.first().and_then(|lat| lat.as_f64()).unwrap_or(0.0);
+        let lon = pair.get(1).and_then(|lon| lon.as_f64()).unwrap_or(0.0);
+
+        // Nothing ever set it, so (0, 0) means unknown
+        let location = if lat == 0.0 && lon == 0.0 {
+            Value::Null
+        } else {
+            changes.push(format!("user {}: location ({}, {})", uuid, lat, lon));
+            json!({ "lat": lat, "lon": lon })
+        };
+
+        user.insert("current_location".to_string(), location);
+    }
+
+    Ok(changes)
+}
+
+fn v7_normalize_tags(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let feed = match document.get_mut("feed").and_then(|feed| feed.as_array_mut()) {
+        Some(feed) => feed,
+        None => return Ok(changes),
+    };
+
+    for post in feed.iter_mut() {
+        let uuid = post["uuid"].as_str().unwrap_or_default().to_string();
+        let post = post.as_object_mut().ok_or(format!("Post {} is not an object", uuid))?;
+
+        let old: Vec<String> = post.get("tags")
+            .and_then(|tags| tags.as_array())
+            .map(|tags| tags.iter().filter_map(|tag| tag.as_str()).map(str::to_string).collect())
+            .unwrap_or_default();
+
+        // No synonyms have been registered yet, so this is all there is to it
+        let mut tags: Vec<String> = Vec::new();
+        for tag in old.iter().map(|tag| normalize_tag(tag)).filter(|tag| !tag.is_empty()) {
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        if tags != old {
+            changes.push(format!("post {}: tags {:?} -> {:?}", uuid, old, tags));
+            post.insert("tags".to_string(), json!(tags));
+        }
+    }
+
+    Ok(changes)
+}
+
+fn v8_conversation_threads(document: &mut Value, _config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    let posts: Vec<Value> = document["feed"].as_array().cloned().unwrap_or_default();
+
+    let conversations = match document.get_mut("conversations").and_then(|conversations| conversations.as_object_mut()) {
+        Some(conversations) => conversations,
+        None => return Ok(changes),
+    };
+
+    for (post_uuid, conversation) in conversations.iter_mut() {
+        let post = posts.iter().find(|post| post["uuid"].as_str() == Some(post_uuid.as_str()));
+        let owner = post.and_then(|post| post["user_owner"].as_str()).unwrap_or_default().to_string();
+        let current = post.and_then(|post| post["user_acceptor"].as_str()).map(str::to_string);
+
+        let messages = match conversation.get_mut("messages").and_then(|messages| messages.as_array_mut()) {
+            Some(messages) => messages,
+            None => continue,
+        };
+
+        // Whoever else was writing at the time. The owner's messages go to
+        // the thread before them, or the first one if they wrote first.
+        let senders: Vec<Option<String>> = messages.iter()
+            .map(|message| message["sender"].as_str().filter(|sender| *sender != owner).map(str::to_string))
+            .collect();
+        let first = senders.iter().flatten().next().cloned().or(current).unwrap_or_default();
+
+        let mut acceptor = first;
+        for (message, sender) in messages.iter_mut().zip(senders) {
+            if let Some(sender) = sender {
+                acceptor = sender;
+            }
+            message["acceptor"] = Value::from(acceptor.clone());
+        }
+
+        changes.push(format!("post {}: {} messages threaded", post_uuid, messages.len()));
+    }
+
+    Ok(changes)
+}
+
+/// Seeding is a migration so it happens once per database; places an admin
+/// deletes later stay deleted
+fn v9_seed_places(document: &mut Value, config: &Config) -> Result<Vec<String>, String> {
+    let mut changes = Vec::new();
+
+    // Seeded at startup by older builds
+    let seeded = document["places"].as_object().is_some_and(|places| !places.is_empty());
+    if seeded || config.gazetteer_file.is_empty() {
+        return Ok(changes);
+    }
+
+    let places = match read_gazetteer(&config.gazetteer_file) {
+        Ok(places) => places,
+        // Admins can still add places by hand
+        Err(e) => {
+            warn!("Failed to load gazetteer: {}", e);
+            return Ok(changes);
+        }
+    };
+
+    let mut seed = serde_json::Map::new();
+    for place in places {
+        if let Err(e) = place.check() {
+            changes.push(format!("place {}: skipped, {}", place.id, e));
+            continue;
+        }
+
+        changes.push(format!("place {}: added", place.id));
+        seed.insert(place.id.clone(), serde_json::to_value(&place).map_err(|e| e.to_string())?);
+    }
+    document["places"] = Value::Object(seed);
+
+    Ok(changes)
+}
+
+#[derive(Default, Debug)]
+pub struct MigrationReport {
+    pub from: u32,
+    pub to: u32,
+    /// Description and changes of each migration that ran
+    pub steps: Vec<(&'static str, Vec<String>)>,
+}
+
+impl MigrationReport {
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    pub fn print(&self) {
+        if self.is_empty() {
+            println!("Schema is up to date (version {})", self.to);
+            return;
+        }
+
+        println!("Schema version {} -> {}", self.from, self.to);
+        for (description, changes) in self.steps.iter() {
+            println!("  * {} ({} changes)", description, changes.len());
+            for change in changes {
+                println!("      - {}", change);
+            }
+        }
+    }
+}
+
+fn document_version(document: &Value) -> u32 {
+    document["schema_version"].as_u64().unwrap_or(0) as u32
+}
+
+/// Run every migration the document needs to reach `SCHEMA_VERSION`
+pub fn upgrade(document: &mut Value, config: &Config) -> Result<MigrationReport, String> {
+    if !document.is_object() {
+        return Err("Database document is not a JSON object".to_string());
+    }
+
+    let from = document_version(document);
+
+    if from > SCHEMA_VERSION {
+        return Err(format!(
+            "Database schema version {} is newer than this server supports ({})",
+            from, SCHEMA_VERSION
+        ));
+    }
+
+    let mut report = MigrationReport { from, to: from, steps: Vec::new() };
+
+    for migration in MIGRATIONS.iter().filter(|m| m.from >= from) {
+        if migration.from != report.to {
+            return Err(format!("No migration from schema version {}", report.to));
+        }
+
+        let changes = (migration.run)(document, config)
+            .map_err(|e| format!("Migration from version {} failed: {}", migration.from, e))?;
+
+        report.to = migration.from + 1;
+        document["schema_version"] = Value::from(report.to);
+        report.steps.push((migration.description, changes));
+    }
+
+    if report.to != SCHEMA_VERSION {
+        return Err(format!("No migration from schema version {}", report.to));
+    }
+
+    Ok(report)
+}
+
+/// Upgrade `document`, print what changed and make sure the result loads
+fn upgrade_and_check(mut document: Value, config: &Config) -> Result<(MigrationReport, Data), String> {
+    let report = upgrade(&mut document, config)?;
+    report.print();
+
+    let data: Data = serde_json::from_value(document).map_err(|e| e.to_string())?;
+    println!("Upgraded document has {} users and {} posts", data.users.len(), data.feed.len());
+
+    Ok((report, data))
+}
+
+/// `--migrate`: upgrade the stored database and exit
+pub fn run_migrations(storage: Arc<dyn Storage>, config: &Config) -> Result<(), String> {
+    let (report, data) = upgrade_and_check(storage.load_document()?, config)?;
+
+    storage.save(&data)?;
+    info!("Migrated database to schema version {}", report.to);
+
+    Ok(())
+}
+
+/// `--migrate --dry-run`: show what `--migrate` would do, writing nothing,
+/// not even a new log or database file
+pub fn preview_migrations(config: &Config) -> Result<(), String> {
+    upgrade_and_check(preview_document(config)?, config)?;
+    println!("Dry run, nothing written");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn places_are_seeded_once() {
+        let path = std::env::temp_dir().join(format!("iso-{}.json", uuid::Uuid::new_v4()));
+        let places = json!([
+            { "id": "hoch", "name": "Hoch Dining Hall", "aliases": ["the hoch"], "location": { "lat": 34.1, "lon": -117.7 } },
+            { "id": "mudd", "name": "Mudd Library", "aliases": [], "location": { "lat": 34.2, "lon": -117.7 } },
+        ]);
+        fs::write(&path, places.to_string()).unwrap();
+
+        let config = Config { gazetteer_file: path.to_string_lossy().to_string(), ..Config::default() };
+
+        let mut document = json!({});
+        let report = upgrade(&mut document, &config);
+        assert!(report.unwrap().steps.iter().any(|(_, changes)| changes.contains(&"place hoch: added".to_string())));
+
+        let mut data: Data = serde_json::from_value(document).unwrap();
+        assert_eq!(data.places.len(), 2);
+
+        // An admin deletes one, and the server restarts
+        data.places.remove("mudd");
+        let mut document = serde_json::to_value(&data).unwrap();
+        let report = upgrade(&mut document, &config);
+        fs::remove_file(&path).unwrap();
+
+        assert!(report.unwrap().is_empty());
+        let data: Data = serde_json::from_value(document).unwrap();
+        assert!(data.places.contains_key("hoch") && !data.places.contains_key("mudd"));
+    }
+}
@@ -1,24 +1,72 @@
+use std::fmt;
 use std::time::SystemTime;
 
 use phonenumber::country::Id::SY;
 use ::serde::{Deserialize, Serialize};
 
+use crate::{data::now, geo::Coordinate, view::{ParticipantPostView, PublicPostView, PublicUserView}};
 
-#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+/// Seconds a new post stays up before it expires, unless configured
+pub const POST_LIFETIME: u64 = 86400;
+
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum PostType {
     #[default] ISO,
     OSI,
 }
 
-#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+/// Where a post is in its lifecycle.
+///
+/// ```text
+/// Draft --publish--> Posted --approve claim--> Accepted --both complete--> Completed
+///                    ^  |                         |
+///                    |  +--expire--> Expired      |
+///                    +----reopen------------------+ (also from Expired and Cancelled)
+/// ```
+///
+/// While `Posted`, any number of users can request to claim a post; the
+/// owner approves one of them. The owner can cancel anything that isn't
+/// finished yet.
+#[derive(Default, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
 pub enum PostState {
     #[default] Draft,
     Posted,
     Accepted,
     Expired,
+    Completed,
+    Cancelled,
 }
 
-#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+/// Why a post can't make the requested transition
+#[derive(Clone, Debug, PartialEq)]
+pub enum PostError {
+    InvalidTransition { from: PostState, action: &'static str },
+    NotOwner,
+    OwnPost,
+    AlreadyClaimed,
+    AlreadyRequested,
+    NoSuchClaim,
+    NotParticipant,
+    Expired,
+}
+
+impl fmt::Display for PostError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PostError::InvalidTransition { from, action } => write!(f, "Cannot {} a post that is {:?}", action, from),
+            PostError::NotOwner => write!(f, "Only the owner can do that"),
+            PostError::OwnPost => write!(f, "You cannot claim your own post"),
+            PostError::AlreadyClaimed => write!(f, "Post has already been claimed"),
+            PostError::AlreadyRequested => write!(f, "You have already offered to claim this post"),
+            PostError::NoSuchClaim => write!(f, "No pending claim from that user"),
+            PostError::NotParticipant => write!(f, "Only the owner or acceptor can do that"),
+            PostError::Expired => write!(f, "Post has expired"),
+        }
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug, PartialEq)]
 pub enum TimeType {
     ServiceNow,
     ServiceFuture,
@@ -26,20 +74,167 @@ pub enum TimeType {
     ItemLoan,
 }
 
+/// Seconds a post of each `TimeType` stays up, set in `config.toml`
+#[derive(Deserialize, Serialize, Clone, Debug)]
+#[serde(default, rename_all = "PascalCase")]
+pub struct PostLifetimes {
+    pub service_now: u64,
+    pub service_future: u64,
+    pub item_permanant: u64,
+    pub item_loan: u64,
+}
+
+impl Default for PostLifetimes {
+    fn default() -> Self {
+        PostLifetimes {
+            service_now: POST_LIFETIME,
+            service_future: POST_LIFETIME,
+            item_permanant: POST_LIFETIME,
+            item_loan: POST_LIFETIME,
+        }
+    }
+}
+
+impl PostLifetimes {
+    pub fn get(&self, time_type: &TimeType) -> u64 {
+        match time_type {
+            TimeType::ServiceNow => self.service_now,
+            TimeType::ServiceFuture => self.service_future,
+            TimeType::ItemPermanant => self.item_permanant,
+            TimeType::ItemLoan => self.item_loan,
+        }
+    }
+}
+
+/// Which side of a post a user is on
+#[derive(Default, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum PostRole {
+    #[default] Owner,
+    Acceptor,
+}
+
+/// Whether a post needs any or all of the tags asked for
+#[derive(Default, Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum TagMode {
+    #[default] Any,
+    All,
+}
+
+/// Which posts a feed reader wants, from the query string. Empty matches
+/// everything.
 #[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct FeedFilter {
+    pub post_type: Option<PostType>,
+    pub time_type: Option<TimeType>,
+    /// Comma-separated
+    pub tags: Option<String>,
+    pub tag_mode: TagMode,
+    /// UUID of the post's owner
+    pub owner: Option<String>,
+    pub state: Option<PostState>,
+}
+
+impl FeedFilter {
+    /// Drafts and cancelled posts are only ever shown to their owner
+    pub fn check(&self) -> Result<(), String> {
+        match self.state {
+            Some(state @ (PostState::Draft | PostState::Cancelled)) => Err(format!("Cannot list {:?} posts", state)),
+            _ => Ok(()),
+        }
+    }
+
+    fn tag_list(&self) -> Vec<String> {
+        self.tags.iter()
+            .flat_map(|tags| tags.split(','))
+            .map(|tag| tag.trim().to_lowercase())
+            .filter(|tag| !tag.is_empty())
+            .collect()
+    }
+
+    fn matches_tags(&self, post: &Post) -> bool {
+        let wanted = self.tag_list();
+        if wanted.is_empty() {
+            return true;
+        }
+
+        let has = |tag: &String| post.tags.iter().any(|x| &x.to_lowercase() == tag);
+
+        match self.tag_mode {
+            TagMode::Any => wanted.iter().any(has),
+            TagMode::All => wanted.iter().all(has),
+        }
+    }
+
+    pub fn matches(&self, post: &Post) -> bool {
+        self.state.map_or(true, |state| post.state == state) && self.matches_fields(post)
+    }
+
+    /// Everything but `state`
+    pub fn matches_fields(&self, post: &Post) -> bool {
+        self.post_type.as_ref().map_or(true, |post_type| &post.iso_or_osi == post_type)
+            && self.time_type.as_ref().map_or(true, |time_type| &post.time_type == time_type)
+            && self.owner.as_ref().map_or(true, |owner| &post.user_owner == owner)
+            && self.matches_tags(post)
+    }
+}
+
+/// A pending offer to fulfill a post
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct ClaimRequest {
+    pub user_uuid: String,
+    pub time_requested: u64,
+}
+
+/// Requested changes to a post; `None` leaves a field alone
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PostEdit {
+    pub title: Option<String>,
+    pub tags: Option<Vec<String>>,
+    pub location_string: Option<String>,
+    pub location: Option<Coordinate>,
+}
+
+/// The editable fields of a post as they were before an edit
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct PostRevision {
+    pub time_edited: u64,
+    pub title: String,
+    pub tags: Vec<String>,
+    pub location_string: String,
+    pub location: Option<Coordinate>,
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
 pub struct Post {
     pub uuid: String,
     pub title: String,
     pub iso_or_osi: PostType,
     pub state: PostState,
     pub location_string: String,
+    /// Where the post is, given by the owner or from its place
+    pub location: Option<Coordinate>,
+    /// Gazetteer place matched from `location_string`, see `gazetteer`
+    pub place_id: Option<String>,
     
     pub time_posted: u64,
     pub time_expires: u64,
     pub time_accepted: Option<u64>,
+    pub time_completed: Option<u64>,
 
     user_owner: String,
     user_acceptor: Option<String>,
+    /// Offers waiting on the owner, oldest first
+    claim_requests: Vec<ClaimRequest>,
+    /// Each side's confirmation that an accepted post is done
+    owner_completed: bool,
+    acceptor_completed: bool,
 
     karma_diff: i32,
 
@@ -47,6 +242,9 @@ pub struct Post {
 
     time_type: TimeType,
     tags: Vec<String>,
+
+    /// Previous versions, oldest first
+    revisions: Vec<PostRevision>,
 }
 
 impl Post {
@@ -70,7 +268,7 @@ impl Post {
         post.time_expires = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
         .expect("Time went backwards")
-        .as_secs() + 86400;
+        .as_secs() + POST_LIFETIME;
         post.time_accepted = None;
         post.user_owner = owner_uuid;
         post.user_acceptor = None;
@@ -83,12 +281,261 @@ impl Post {
         post
     }
 
-    pub fn claim(&mut self, user_uuid: String) {
-        self.user_acceptor = Some(user_uuid);
+    pub fn get_acceptor(&self) -> Option<String> {
+        self.user_acceptor.clone()
+    }
+
+    pub fn public_view(&self, owner: Option<PublicUserView>) -> PublicPostView {
+        PublicPostView {
+            uuid: self.uuid.clone(),
+            title: self.title.clone(),
+            iso_or_osi: self.iso_or_osi.clone(),
+            state: self.state,
+            location_string: self.location_string.clone(),
+            location: self.location,
+            place_id: self.place_id.clone(),
+            time_posted: self.time_posted,
+            time_expires: self.time_expires,
+            time_accepted: self.time_accepted,
+            time_completed: self.time_completed,
+            time_type: self.time_type.clone(),
+            tags: self.tags.clone(),
+            karma_diff: self.karma_diff,
+            views: self.views,
+            owner,
+            claimed: self.user_acceptor.is_some(),
+        }
+    }
+
+    pub fn participant_view(&self, post: PublicPostView, acceptor: Option<PublicUserView>) -> ParticipantPostView {
+        ParticipantPostView {
+            post,
+            acceptor,
+            claim_requests: self.claim_requests.clone(),
+            owner_completed: self.owner_completed,
+            acceptor_completed: self.acceptor_completed,
+            revisions: self.revisions.clone(),
+        }
+    }
+
+    pub fn get_karma_diff(&self) -> i32 {
+        self.karma_diff
+    }
+
+    /// Past its expiry time, even if the sweeper hasn't marked it yet
+    pub fn is_expired(&self) -> bool {
+        self.state == PostState::Expired || (self.state == PostState::Posted && self.time_expires <= now())
+    }
+
+    fn check_owner(&self, user_uuid: &str) -> Result<(), PostError> {
+        if self.user_owner != user_uuid {
+            return Err(PostError::NotOwner);
+        }
+        Ok(())
+    }
+
+    /// How long this post stays up once published
+    pub fn lifetime(&self, lifetimes: &PostLifetimes) -> u64 {
+        lifetimes.get(&self.time_type)
+    }
+
+    pub fn publish(&mut self, user_uuid: &str, lifetime: u64) -> Result<(), PostError> {
+        self.check_owner(user_uuid)?;
+
+        if self.state != PostState::Draft {
+            return Err(PostError::InvalidTransition { from: self.state, action: "publish" });
+        }
+
+        self.state = PostState::Posted;
+        self.time_posted = now();
+        self.time_expires = self.time_posted + lifetime;
+
+        Ok(())
+    }
+
+    /// Offer to fulfill a post, pending the owner's approval
+    pub fn request_claim(&mut self, user_uuid: String) -> Result<(), PostError> {
+        if self.user_owner == user_uuid {
+            return Err(PostError::OwnPost);
+        }
+        if self.user_acceptor.is_some() {
+            return Err(PostError::AlreadyClaimed);
+        }
+        if self.is_expired() {
+            return Err(PostError::Expired);
+        }
+        if self.state != PostState::Posted {
+            return Err(PostError::InvalidTransition { from: self.state, action: "claim" });
+        }
+        if self.claim_requests.iter().any(|x| x.user_uuid == user_uuid) {
+            return Err(PostError::AlreadyRequested);
+        }
+
+        self.claim_requests.push(ClaimRequest { user_uuid, time_requested: now() });
+
+        Ok(())
+    }
+
+    /// Take back an offer that hasn't been approved yet
+    pub fn withdraw_claim(&mut self, user_uuid: &str) -> Result<(), PostError> {
+        let pos = self.claim_requests.iter().position(|x| x.user_uuid == user_uuid).ok_or(PostError::NoSuchClaim)?;
+        self.claim_requests.remove(pos);
+
+        Ok(())
+    }
+
+    /// The owner picks one of the pending offers. Returns the users whose
+    /// offers were turned down.
+    pub fn approve_claim(&mut self, owner_uuid: &str, acceptor_uuid: &str) -> Result<Vec<String>, PostError> {
+        self.check_owner(owner_uuid)?;
+
+        if self.is_expired() {
+            return Err(PostError::Expired);
+        }
+        if self.state != PostState::Posted {
+            return Err(PostError::InvalidTransition { from: self.state, action: "approve a claim on" });
+        }
+        if !self.claim_requests.iter().any(|x| x.user_uuid == acceptor_uuid) {
+            return Err(PostError::NoSuchClaim);
+        }
+
+        let declined = std::mem::take(&mut self.claim_requests)
+            .into_iter()
+            .map(|x| x.user_uuid)
+            .filter(|uuid| uuid != acceptor_uuid)
+            .collect();
+
+        self.user_acceptor = Some(acceptor_uuid.to_string());
         self.state = PostState::Accepted;
-        self.time_accepted = Some(SystemTime::now()
-        .duration_since(SystemTime::UNIX_EPOCH)
-        .expect("Time went backwards")
-        .as_secs());
+        self.time_accepted = Some(now());
+
+        Ok(declined)
     }
-}
\ No newline at end of file
+
+    /// Owner or acceptor confirms the post is done. It completes once both
+    /// have.
+    pub fn complete(&mut self, user_uuid: &str) -> Result<(), PostError> {
+        if self.state != PostState::Accepted {
+            return Err(PostError::InvalidTransition { from: self.state, action: "complete" });
+        }
+
+        if self.user_owner == user_uuid {
+            self.owner_completed = true;
+        } else if self.user_acceptor.as_deref() == Some(user_uuid) {
+            self.acceptor_completed = true;
+        } else {
+            return Err(PostError::NotParticipant);
+        }
+
+        if self.owner_completed && self.acceptor_completed {
+            self.state = PostState::Completed;
+            self.time_completed = Some(now());
+        }
+
+        Ok(())
+    }
+
+    pub fn cancel(&mut self, user_uuid: &str) -> Result<(), PostError> {
+        self.check_owner(user_uuid)?;
+
+        match self.state {
+            PostState::Draft | PostState::Posted | PostState::Accepted | PostState::Expired => {
+                self.state = PostState::Cancelled;
+                Ok(())
+            }
+            _ => Err(PostError::InvalidTransition { from: self.state, action: "cancel" }),
+        }
+    }
+
+    /// Put the post back on the feed, returning the acceptor it is taken from
+    pub fn reopen(&mut self, user_uuid: &str, lifetime: u64) -> Result<Option<String>, PostError> {
+        self.check_owner(user_uuid)?;
+
+        match self.state {
+            PostState::Accepted | PostState::Expired | PostState::Cancelled => {
+                self.time_expires = now() + lifetime;
+                self.state = PostState::Posted;
+                self.time_accepted = None;
+                self.owner_completed = false;
+                self.acceptor_completed = false;
+                self.claim_requests.clear();
+                Ok(self.user_acceptor.take())
+            }
+            _ => Err(PostError::InvalidTransition { from: self.state, action: "reopen" }),
+        }
+    }
+
+    /// Keep a post up for another `lifetime` seconds from now, bringing it
+    /// back to the feed if it had already expired
+    pub fn renew(&mut self, user_uuid: &str, lifetime: u64) -> Result<(), PostError> {
+        self.check_owner(user_uuid)?;
+
+        match self.state {
+            PostState::Posted | PostState::Expired => {
+                self.state = PostState::Posted;
+                self.time_expires = std::cmp::max(self.time_expires, now()) + lifetime;
+                Ok(())
+            }
+            _ => Err(PostError::InvalidTransition { from: self.state, action: "renew" }),
+        }
+    }
+
+    /// Apply an owner's edit, keeping the old values as a revision
+    pub fn edit(&mut self, user_uuid: &str, edit: PostEdit) -> Result<(), PostError> {
+        self.check_owner(user_uuid)?;
+
+        if self.state == PostState::Completed || self.state == PostState::Cancelled {
+            return Err(PostError::InvalidTransition { from: self.state, action: "edit" });
+        }
+
+        let revision = PostRevision {
+            time_edited: now(),
+            title: self.title.clone(),
+            tags: self.tags.clone(),
+            location_string: self.location_string.clone(),
+            location: self.location,
+        };
+
+        if let Some(title) = edit.title {
+            self.title = title;
+        }
+        if let Some(tags) = edit.tags {
+            self.tags = tags;
+        }
+        if let Some(location_string) = edit.location_string {
+            self.location_string = location_string;
+        }
+        if let Some(location) = edit.location {
+            self.location = Some(location);
+        }
+
+        let changed = self.title != revision.title
+            || self.tags != revision.tags
+            || self.location_string != revision.location_string
+            || self.location != revision.location;
+
+        if changed {
+            self.revisions.push(revision);
+        }
+
+        Ok(())
+    }
+
+    pub fn get_tags(&self) -> &Vec<String> {
+        &self.tags
+    }
+
+    pub fn set_tags(&mut self, tags: Vec<String>) {
+        self.tags = tags;
+    }
+
+    pub fn expire(&mut self) -> Result<(), PostError> {
+        if self.state != PostState::Posted {
+            return Err(PostError::InvalidTransition { from: self.state, action: "expire" });
+        }
+
+        self.state = PostState::Expired;
+
+        Ok(())
+    }
+}
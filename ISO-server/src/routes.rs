@@ -3,16 +3,55 @@ use ::serde::{Deserialize, Serialize};
 use actix_web::error::*;
 use serde_json::json;
 use crate::data::*;
+use crate::auth::{AdminUser, AuthedUser, SocketUser, BEARER_PROTOCOL};
+use crate::view::{ConversationView, KarmaEntryView, NearbyPostView, OwnSessionView, PostView};
+use crate::events::{EVENTS, FEED, SHUTDOWN};
+use crate::feed::{FeedPageQuery, MAX_FEED_PAGE_SIZE};
+use crate::gazetteer::{Place, PlaceEdit};
+use crate::tags::{TagEdit, TAG_LIST_SIZE, TRENDING_HOURS};
+use crate::geo::Coordinate;
+use tokio::sync::broadcast::error::RecvError;
 
 
+/// Offset paging, kept for older clients. See `get_feed` instead.
 #[get("/api/v1/posts/feedPage/{index}")]
 pub async fn get_post_page(
     index: web::Path<usize>,
+    filter: web::Query<FeedFilter>,
 ) -> Result<HttpResponse, Error> {
+    if let Err(e) = filter.check() {
+        let json = json!({
+            "error": e
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    }
+
     let data = db_clone().await;
     let index = index.into_inner();
+    let filter = data.canonical_filter(filter.into_inner());
+
+    let posts = data.visible_posts(&filter);
+    let end = std::cmp::min(index.saturating_add(FEED_PAGE_SIZE), posts.len());
+    let page: Vec<Post> = posts.get(index..end).unwrap_or_default().iter().map(|post| (*post).clone()).collect();
+
+    let json = json!({
+        "results": PostView::list(&data, &page, None),
+        "next": std::cmp::max(index, end),
+    });
 
-    let page = data.get_feed_page(index);
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[get("/api/v1/posts/feed")]
+pub async fn get_feed(
+    filter: web::Query<FeedFilter>,
+    query: web::Query<FeedPageQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+    let filter = data.canonical_filter(filter.into_inner());
+
+    let page = data.get_feed(&filter, &query);
 
     if page.is_err() {
         let json = json!({
@@ -21,52 +60,295 @@ pub async fn get_post_page(
 
         return Ok(HttpResponse::BadRequest().json(json));
     } else {
-        let mut index_offset = 0;
-        let mut pages = Vec::new();
+        let page = page.unwrap();
 
-        while pages.len() < FEED_PAGE_SIZE && index + index_offset < data.feed.len() {
-            if data.feed[index + index_offset].time_accepted.is_none() {
-                pages.push(data.feed[index + index_offset].clone());
-            }
+        let json = json!({
+            "results": PostView::list(&data, &page.items, None),
+            "next": page.next,
+            "prev": page.prev,
+        });
 
-            index_offset += 1;
-        }
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct SearchQuery {
+    pub q: String,
+}
+
+#[get("/api/v1/posts/search")]
+pub async fn search_posts(
+    search: web::Query<SearchQuery>,
+    filter: web::Query<FeedFilter>,
+    query: web::Query<FeedPageQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+    let filter = data.canonical_filter(filter.into_inner());
+
+    let page = data.search_posts(&search.q, &filter, &query);
+
+    if page.is_err() {
+        let json = json!({
+            "error": page.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let page = page.unwrap();
+
+        let json = json!({
+            "results": PostView::list(&data, &page.items, None),
+            "next": page.next,
+            "prev": page.prev,
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct NearbyQuery {
+    /// Defaults to the user's own location
+    pub lat: Option<f64>,
+    pub lon: Option<f64>,
+    pub radius_km: Option<f64>,
+    pub limit: Option<usize>,
+}
+
+/// Default radius of a nearby search
+const NEARBY_RADIUS_KM: f64 = 10.0;
+
+#[get("/api/v1/posts/nearby")]
+pub async fn get_nearby_posts(
+    user: Option<AuthedUser>,
+    filter: web::Query<FeedFilter>,
+    query: web::Query<NearbyQuery>,
+) -> Result<HttpResponse, Error> {
+    let center = match (query.lat, query.lon) {
+        (Some(lat), Some(lon)) => Some(Coordinate { lat, lon }),
+        _ => user.as_ref().and_then(|user| user.user.get_location()),
+    };
+
+    if center.is_none() {
+        let json = json!({
+            "error": "Give lat and lon, or set your location first"
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    }
+
+    let limit = std::cmp::min(query.limit.unwrap_or(FEED_PAGE_SIZE), MAX_FEED_PAGE_SIZE);
+    let radius_km = query.radius_km.unwrap_or(NEARBY_RADIUS_KM);
+
+    let data = db_clone().await;
+    let filter = data.canonical_filter(filter.into_inner());
+
+    let nearby = data.get_nearby(&center.unwrap(), radius_km, &filter, limit);
+
+    if nearby.is_err() {
+        let json = json!({
+            "error": nearby.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let viewer = user.as_ref().map(|user| user.user.uuid.as_str());
+
+        let results: Vec<_> = nearby.unwrap().iter()
+            .map(|(post, distance_km)| NearbyPostView { post: PostView::new(&data, post, viewer), distance_km: *distance_km })
+            .collect();
 
         let json = json!({
-            "results": pages,
-            "next": index + index_offset,
+            "results": results,
         });
 
         return Ok(HttpResponse::Ok().json(json));
     }
 }
 
+/// Seconds between keep-alive comments on an idle feed stream
+const STREAM_KEEP_ALIVE: u64 = 15;
+
+/// Server-Sent Events of feed changes matching the same filters as `feedPage`
+#[get("/api/v1/posts/stream")]
+pub async fn stream_posts(
+    filter: web::Query<FeedFilter>,
+) -> Result<HttpResponse, Error> {
+    if let Err(e) = filter.check() {
+        let json = json!({
+            "error": e
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    }
+
+    let filter = db_mut().await.canonical_filter(filter.into_inner());
+    let updates = FEED.subscribe();
+    let shutdown = SHUTDOWN.subscribe();
+
+    let stream = futures::stream::unfold((updates, shutdown, filter), |(mut updates, mut shutdown, filter)| async move {
+        loop {
+            let received = tokio::select! {
+                received = time::timeout(Duration::from_secs(STREAM_KEEP_ALIVE), updates.recv()) => received,
+                // End the response so the server can finish draining
+                _ = shutdown.wait_for(|stopping| *stopping) => return None,
+            };
+
+            let update = match received {
+                Ok(Ok(update)) => update,
+                // Too slow to keep up; tell the client to refetch
+                Ok(Err(RecvError::Lagged(_))) => {
+                    let frame = web::Bytes::from("event: Resync\ndata: {}\n\n");
+                    return Some((Ok::<_, Error>(frame), (updates, shutdown, filter)));
+                }
+                Ok(Err(RecvError::Closed)) => return None,
+                Err(_) => {
+                    return Some((Ok(web::Bytes::from(": keep-alive\n\n")), (updates, shutdown, filter)));
+                }
+            };
+
+            if !filter.matches(&update.post) {
+                continue;
+            }
+
+            let data = serde_json::to_string(&update.view).unwrap_or_default();
+            let frame = format!("event: {:?}\ndata: {}\n\n", update.kind, data);
+            return Some((Ok(web::Bytes::from(frame)), (updates, shutdown, filter)));
+        }
+    });
+
+    Ok(HttpResponse::Ok()
+        .content_type("text/event-stream")
+        .insert_header(("Cache-Control", "no-cache"))
+        .streaming(stream))
+}
+
 #[post("/api/v1/users/userInfo")]
 pub async fn get_user_info(
-    user: web::Json<User>,
+    user: AuthedUser,
+) -> Result<HttpResponse, Error> {
+    let json = json!({
+        "results": user.user.self_view(),
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[patch("/api/v1/users/me")]
+pub async fn edit_profile(
+    user: AuthedUser,
+    edit: web::Json<ProfileEdit>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.edit_profile(&user.user.uuid, edit.into_inner());
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": result.unwrap().self_view(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+/// Anyone's profile, without their phone number, sessions or verification
+#[get("/api/v1/users/{uuid}")]
+pub async fn get_user_profile(
+    uuid: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     let data = db_clone().await;
 
-    let user = data.get_user_by_token(&user.uuid, &user.get_token());
+    let user = data.users.get(uuid.as_str());
+
+    if user.is_none() {
+        let json = json!({
+            "error": "User not found"
+        });
+
+        return Ok(HttpResponse::NotFound().json(json));
+    } else {
+        let json = json!({
+            "results": user.unwrap().public_view(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[post("/api/v1/users/location")]
+pub async fn set_location(
+    user: AuthedUser,
+    location: web::Json<Coordinate>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.set_location(&user.user.uuid, location.into_inner());
 
-    if user.is_err() {
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
         let json = json!({
-            "error": user.err().unwrap()
+            "results": result.unwrap().self_view(),
         });
 
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct UserPostsQuery {
+    pub role: PostRole,
+    pub state: Option<PostState>,
+}
+
+#[get("/api/v1/users/me/posts")]
+pub async fn get_my_posts(
+    user: AuthedUser,
+    posts: web::Query<UserPostsQuery>,
+    query: web::Query<FeedPageQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let page = data.get_user_posts(&user.user.uuid, posts.role, posts.state, &query);
+
+    if page.is_err() {
+        let json = json!({
+            "error": page.err().unwrap()
+        });
 
         return Ok(HttpResponse::BadRequest().json(json));
     } else {
+        let page = page.unwrap();
+
         let json = json!({
-            "results": user.unwrap(),
+            "results": PostView::list(&data, &page.items, Some(&user.user.uuid)),
+            "next": page.next,
+            "prev": page.prev,
         });
 
         return Ok(HttpResponse::Ok().json(json));
     }
 }
 
+/// Signed-in owners and acceptors get the participant view
 #[get("/api/v1/posts/single/{uuid}")]
 pub async fn get_individual_post(
+    user: Option<AuthedUser>,
     uuid: web::Path<String>,
 ) -> Result<HttpResponse, Error> {
     let data = db_clone().await;
@@ -80,8 +362,10 @@ pub async fn get_individual_post(
 
         return Ok(HttpResponse::BadRequest().json(json));
     } else {
+        let viewer = user.as_ref().map(|user| user.user.uuid.as_str());
+
         let json = json!({
-            "results": post.unwrap(),
+            "results": PostView::new(&data, &post.unwrap(), viewer),
         });
 
         return Ok(HttpResponse::Ok().json(json));
@@ -90,18 +374,27 @@ pub async fn get_individual_post(
 
 #[derive(Default, Deserialize, Serialize, Clone, Debug)]
 pub struct NewPost {
-    title: String, post_type: PostType, owner_uuid: String, time_type: TimeType, tags: Vec<String>, location_string: String,
+    title: String, post_type: PostType, time_type: TimeType, tags: Vec<String>, location_string: String,
+    #[serde(default)]
+    location: Option<Coordinate>,
+    /// Keep the post off the feed until it is published
+    #[serde(default)]
+    draft: bool,
 }
 
 #[post("/api/v1/posts/new")] 
 pub async fn new_post(
+    user: AuthedUser,
     post: web::Json<NewPost>,
 ) -> Result<HttpResponse, Error> {
     let post = post.into_inner();
+    let lifetimes = CONFIG.lock().await.post_lifetimes.clone();
 
     let mut db = db_mut().await;
 
-    let result = db.add_post(post.title, post.post_type, post.owner_uuid, post.time_type, post.tags, post.location_string).await;
+    let mut new_post = Post::new(post.title, post.post_type, user.user.uuid, post.time_type, post.tags, post.location_string);
+    new_post.location = post.location;
+    let result = db.add_post(new_post, post.draft, &lifetimes).await;
     drop(db);
 
     if result.is_err() {
@@ -136,18 +429,30 @@ pub async fn start_verification(
 
     let result = User::start_verification(data.phone_number, data.country).await;
 
-    if result.is_ok() {
-        let json = json!({
-            "results": result.unwrap(),
-        });
+    match result {
+        Ok(uuid) => {
+            let json = json!({
+                "results": uuid,
+            });
 
-        return Ok(HttpResponse::Ok().json(json));
-    } else {
-        let json = json!({
-            "error": result.err().unwrap(),
-        });
+            return Ok(HttpResponse::Ok().json(json));
+        }
+        Err(StartVerificationError::Invalid(e)) => {
+            let json = json!({
+                "error": e,
+            });
 
-        return Ok(HttpResponse::BadRequest().json(json));
+            return Ok(HttpResponse::BadRequest().json(json));
+        }
+        Err(StartVerificationError::Storage(e)) => {
+            error!("Failed to save user: {}", e);
+
+            let json = json!({
+                "error": "Failed to save user",
+            });
+
+            return Ok(HttpResponse::InternalServerError().json(json));
+        }
     }
 }
 
@@ -155,6 +460,9 @@ pub async fn start_verification(
 pub struct VerifyCodeJSON {
     pub uuid: String,
     pub code: String,
+    /// Label for the new session
+    #[serde(default)]
+    pub device: String,
 }
 #[post("/api/v1/users/checkVerification")]
 pub async fn check_verification(
@@ -165,13 +473,19 @@ pub async fn check_verification(
 
     info!("Starting verification for {:?}", data);
 
+    let session_lifetime = CONFIG.lock().await.session_lifetime;
+
     let mut db = db_mut().await;
 
-    let result = db.check_verification(data.uuid, data.code).await;
+    let result = db.check_verification(data.uuid.clone(), data.code).await
+        .and_then(|user| Ok((user, db.create_session(&data.uuid, data.device, session_lifetime)?)));
 
     if result.is_ok() {
+        let (user, token) = result.unwrap();
+
         let json = json!({
-            "results": result.unwrap(),
+            "results": user.self_view(),
+            "token": token,
         });
 
         return Ok(HttpResponse::Ok().json(json));
@@ -186,20 +500,181 @@ pub async fn check_verification(
 
 #[derive(Default, Deserialize, Serialize, Clone, Debug)]
 pub struct ClaimPost {
-    pub user: User,
     pub post_uuid: String,
 }
 
 
 #[post("/api/v1/posts/claim")]
 pub async fn claim_post(
+    user: AuthedUser,
     data: web::Json<ClaimPost>,
 ) -> Result<HttpResponse, Error> {
     let data = data.into_inner();
 
     let mut db = db_mut().await;
 
-    let result = db.claim_post(data.post_uuid, data.user);
+    let result = db.claim_post(data.post_uuid, &user.user.uuid);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": "Claim requested successfully",
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct PostAction {
+    pub post_uuid: String,
+}
+
+fn post_action_response(data: &Data, user: &AuthedUser, result: Result<Post, String>) -> Result<HttpResponse, Error> {
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": PostView::new(data, &result.unwrap(), Some(&user.user.uuid)),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[post("/api/v1/posts/publish")]
+pub async fn publish_post(
+    user: AuthedUser,
+    data: web::Json<PostAction>,
+) -> Result<HttpResponse, Error> {
+    let lifetimes = CONFIG.lock().await.post_lifetimes.clone();
+    let mut db = db_mut().await;
+
+    let result = db.publish_post(&data.post_uuid, &user.user.uuid, &lifetimes);
+
+    post_action_response(&db, &user, result)
+}
+
+#[post("/api/v1/posts/cancel")]
+pub async fn cancel_post(
+    user: AuthedUser,
+    data: web::Json<PostAction>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.cancel_post(&data.post_uuid, &user.user.uuid);
+
+    post_action_response(&db, &user, result)
+}
+
+#[post("/api/v1/posts/complete")]
+pub async fn complete_post(
+    user: AuthedUser,
+    data: web::Json<PostAction>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.complete_post(&data.post_uuid, &user.user.uuid);
+
+    post_action_response(&db, &user, result)
+}
+
+#[post("/api/v1/posts/reopen")]
+pub async fn reopen_post(
+    user: AuthedUser,
+    data: web::Json<PostAction>,
+) -> Result<HttpResponse, Error> {
+    let lifetimes = CONFIG.lock().await.post_lifetimes.clone();
+    let mut db = db_mut().await;
+
+    let result = db.reopen_post(&data.post_uuid, &user.user.uuid, &lifetimes);
+
+    post_action_response(&db, &user, result)
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct RenewPost {
+    pub post_uuid: String,
+    /// Seconds to extend by, or the post's default lifetime if omitted
+    #[serde(default)]
+    pub seconds: u64,
+}
+
+#[post("/api/v1/posts/renew")]
+pub async fn renew_post(
+    user: AuthedUser,
+    data: web::Json<RenewPost>,
+) -> Result<HttpResponse, Error> {
+    let lifetimes = CONFIG.lock().await.post_lifetimes.clone();
+    let mut db = db_mut().await;
+
+    let result = db.renew_post(&data.post_uuid, &user.user.uuid, data.seconds, &lifetimes);
+
+    post_action_response(&db, &user, result)
+}
+
+#[post("/api/v1/posts/withdrawClaim")]
+pub async fn withdraw_claim(
+    user: AuthedUser,
+    data: web::Json<PostAction>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.withdraw_claim(&data.post_uuid, &user.user.uuid);
+
+    post_action_response(&db, &user, result)
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct ApproveClaim {
+    pub post_uuid: String,
+    /// The user whose offer is accepted
+    pub user_uuid: String,
+}
+
+#[post("/api/v1/posts/approveClaim")]
+pub async fn approve_claim(
+    user: AuthedUser,
+    data: web::Json<ApproveClaim>,
+) -> Result<HttpResponse, Error> {
+    let karma_floor = CONFIG.lock().await.karma_floor;
+    let mut db = db_mut().await;
+
+    let result = db.approve_claim(&data.post_uuid, &user.user.uuid, &data.user_uuid, karma_floor);
+
+    post_action_response(&db, &user, result)
+}
+
+#[patch("/api/v1/posts/single/{uuid}")]
+pub async fn edit_post(
+    user: AuthedUser,
+    uuid: web::Path<String>,
+    edit: web::Json<PostEdit>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.edit_post(&uuid, &user.user.uuid, edit.into_inner());
+
+    post_action_response(&db, &user, result)
+}
+
+#[delete("/api/v1/posts/single/{uuid}")]
+pub async fn delete_post(
+    user: AuthedUser,
+    uuid: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.delete_post(&uuid, &user.user.uuid);
 
     if result.is_err() {
         let json = json!({
@@ -209,9 +684,519 @@ pub async fn claim_post(
         return Ok(HttpResponse::BadRequest().json(json));
     } else {
         let json = json!({
-            "results": "Post claimed successfully",
+            "results": "Post deleted successfully",
         });
 
         return Ok(HttpResponse::Ok().json(json));
     }
-}
\ No newline at end of file
+}
+
+#[get("/api/v1/users/sessions")]
+pub async fn get_sessions(
+    user: AuthedUser,
+) -> Result<HttpResponse, Error> {
+    let sessions: Vec<_> = user.user.get_sessions().iter()
+        .filter(|session| !session.is_expired())
+        .map(|session| OwnSessionView { session: session.view(), current: session.id == user.session_id })
+        .collect();
+
+    let json = json!({
+        "results": sessions,
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct RevokeSession {
+    pub session_id: String,
+}
+
+#[post("/api/v1/users/sessions/revoke")]
+pub async fn revoke_session(
+    user: AuthedUser,
+    data: web::Json<RevokeSession>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.revoke_session(&user.user.uuid, &data.session_id);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": "Session revoked",
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[post("/api/v1/users/logoutAll")]
+pub async fn logout_all(
+    user: AuthedUser,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.revoke_all_sessions(&user.user.uuid);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": result.unwrap(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[get("/api/v1/users/{uuid}/karma/history")]
+pub async fn get_karma_history(
+    user: AuthedUser,
+    uuid: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let history = data.karma_history(&uuid);
+
+    if history.is_err() {
+        let json = json!({
+            "error": history.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    }
+
+    let entries: Vec<_> = history.unwrap().iter()
+        .map(|entry| KarmaEntryView::new(entry, &uuid, &user.user.uuid))
+        .collect();
+
+    let json = json!({
+        "results": entries,
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct SendMessage {
+    pub post_uuid: String,
+    pub body: String,
+}
+
+#[post("/api/v1/messages/send")]
+pub async fn send_message(
+    user: AuthedUser,
+    data: web::Json<SendMessage>,
+) -> Result<HttpResponse, Error> {
+    let data = data.into_inner();
+
+    let mut db = db_mut().await;
+
+    let result = db.send_message(&data.post_uuid, &user.user.uuid, data.body);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": result.unwrap(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[get("/api/v1/messages/conversations")]
+pub async fn get_conversations(
+    user: AuthedUser,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let conversations: Vec<_> = data.get_conversations(&user.user.uuid).iter()
+        .map(|(post, acceptor, conversation)| ConversationView::new(&data, post, acceptor, conversation, &user.user.uuid))
+        .collect();
+
+    let json = json!({
+        "results": conversations,
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct ThreadQuery {
+    /// For owners, the acceptor whose thread to read; the current one if missing
+    pub with: Option<String>,
+}
+
+#[get("/api/v1/messages/{post_uuid}")]
+pub async fn get_messages(
+    user: AuthedUser,
+    post_uuid: web::Path<String>,
+    thread: web::Query<ThreadQuery>,
+    query: web::Query<FeedPageQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let page = data.get_messages(&post_uuid, &user.user.uuid, thread.with.as_deref(), &query);
+
+    if page.is_err() {
+        let json = json!({
+            "error": page.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let page = page.unwrap();
+
+        let json = json!({
+            "results": page.items,
+            "next": page.next,
+            "prev": page.prev,
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct MarkRead {
+    pub post_uuid: String,
+    /// Last message read, or the whole thread if missing
+    #[serde(default)]
+    pub message_id: Option<String>,
+    /// For owners, the acceptor whose thread was read; the current one if missing
+    #[serde(default)]
+    pub with: Option<String>,
+}
+
+#[post("/api/v1/messages/read")]
+pub async fn mark_read(
+    user: AuthedUser,
+    data: web::Json<MarkRead>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.mark_read(&data.post_uuid, &user.user.uuid, data.with.as_deref(), data.message_id.as_deref());
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": {
+                "unread": result.unwrap(),
+            },
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct EventsQuery {
+    /// `seq` of the last event the client saw
+    pub cursor: Option<u64>,
+}
+
+/// Push the user's events over a WebSocket until either side closes it
+#[get("/api/v1/events")]
+pub async fn events_socket(
+    user: SocketUser,
+    query: web::Query<EventsQuery>,
+    req: HttpRequest,
+    body: web::Payload,
+) -> Result<HttpResponse, Error> {
+    let (mut response, mut session, mut stream) = actix_ws::handle(&req, body)?;
+
+    // Browsers drop the connection unless the server picks a protocol they
+    // offered
+    if user.protocol {
+        response.headers_mut().insert(http::header::SEC_WEBSOCKET_PROTOCOL, http::header::HeaderValue::from_static(BEARER_PROTOCOL));
+    }
+
+    let mut events = EVENTS.subscribe(&user.user.uuid, query.cursor);
+    let mut shutdown = SHUTDOWN.subscribe();
+
+    spawn(async move {
+        loop {
+            tokio::select! {
+                _ = shutdown.wait_for(|stopping| *stopping) => break,
+                event = events.recv() => {
+                    let event = match event {
+                        Some(event) => event,
+                        None => break,
+                    };
+
+                    let text = serde_json::to_string(&event).unwrap_or_default();
+                    if session.text(text).await.is_err() {
+                        return;
+                    }
+                }
+                message = stream.recv() => {
+                    match message {
+                        Some(Ok(actix_ws::Message::Ping(bytes))) => {
+                            if session.pong(&bytes).await.is_err() {
+                                return;
+                            }
+                        }
+                        Some(Ok(actix_ws::Message::Close(_))) | Some(Err(_)) | None => break,
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        let _ = session.close(None).await;
+    });
+
+    Ok(response)
+}
+
+#[get("/api/v1/places")]
+pub async fn get_places() -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let json = json!({
+        "results": data.get_places(),
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct AutocompleteQuery {
+    pub q: String,
+}
+
+/// Places for the new-post form, as the user types a location
+#[get("/api/v1/places/autocomplete")]
+pub async fn autocomplete_places(
+    query: web::Query<AutocompleteQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let json = json!({
+        "results": data.suggest_places(&query.q),
+    });
+
+    return Ok(HttpResponse::Ok().json(json));
+}
+
+#[put("/api/v1/places/{id}")]
+pub async fn put_place(
+    admin: AdminUser,
+    id: web::Path<String>,
+    edit: web::Json<PlaceEdit>,
+) -> Result<HttpResponse, Error> {
+    let edit = edit.into_inner();
+    let place = Place { id: id.into_inner(), name: edit.name, aliases: edit.aliases, location: edit.location };
+
+    let mut db = db_mut().await;
+
+    let result = db.put_place(place);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let place = result.unwrap();
+        info!("{} saved place {}", admin.user.uuid, place.id);
+
+        let json = json!({
+            "results": place,
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[delete("/api/v1/places/{id}")]
+pub async fn delete_place(
+    admin: AdminUser,
+    id: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.delete_place(&id);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        info!("{} deleted place {}", admin.user.uuid, id);
+
+        let json = json!({
+            "results": "Place deleted",
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct TagsQuery {
+    /// Only tags starting with this, for suggestions
+    pub prefix: Option<String>,
+    pub limit: Option<usize>,
+}
+
+#[get("/api/v1/tags")]
+pub async fn get_tags(
+    query: web::Query<TagsQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let counts = data.tag_counts(query.prefix.as_deref(), query.limit.unwrap_or(TAG_LIST_SIZE));
+
+    if counts.is_err() {
+        let json = json!({
+            "error": counts.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": counts.unwrap(),
+            "registered": data.get_tags(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct TrendingQuery {
+    pub hours: Option<u64>,
+    pub limit: Option<usize>,
+}
+
+#[get("/api/v1/tags/trending")]
+pub async fn get_trending_tags(
+    query: web::Query<TrendingQuery>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let counts = data.trending_tags(query.hours.unwrap_or(TRENDING_HOURS), query.limit.unwrap_or(TAG_LIST_SIZE));
+
+    if counts.is_err() {
+        let json = json!({
+            "error": counts.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let json = json!({
+            "results": counts.unwrap(),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[put("/api/v1/tags/{name}")]
+pub async fn put_tag(
+    admin: AdminUser,
+    name: web::Path<String>,
+    edit: web::Json<TagEdit>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.put_tag(&name, edit.into_inner());
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        let tag = result.unwrap();
+        info!("{} saved tag {}", admin.user.uuid, tag.name);
+
+        let json = json!({
+            "results": tag,
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+#[delete("/api/v1/tags/{name}")]
+pub async fn delete_tag(
+    admin: AdminUser,
+    name: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let mut db = db_mut().await;
+
+    let result = db.delete_tag(&name);
+
+    if result.is_err() {
+        let json = json!({
+            "error": result.err().unwrap()
+        });
+
+        return Ok(HttpResponse::BadRequest().json(json));
+    } else {
+        info!("{} deleted tag {}", admin.user.uuid, name);
+
+        let json = json!({
+            "results": "Tag deleted",
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
+
+/// Everything about a user but their token hashes, for operators
+#[get("/api/v1/admin/users/{uuid}")]
+pub async fn get_admin_user(
+    _admin: AdminUser,
+    uuid: web::Path<String>,
+) -> Result<HttpResponse, Error> {
+    let data = db_clone().await;
+
+    let user = data.users.get(uuid.as_str());
+    let history = data.karma_history(&uuid);
+
+    if user.is_none() || history.is_err() {
+        let json = json!({
+            "error": "User not found"
+        });
+
+        return Ok(HttpResponse::NotFound().json(json));
+    } else {
+        let json = json!({
+            "results": user.unwrap().admin_view(history.unwrap()),
+        });
+
+        return Ok(HttpResponse::Ok().json(json));
+    }
+}
@@ -0,0 +1,245 @@
+use crate::{data::{Data, FEED_PAGE_SIZE}, feed::{from_hex, paginate, to_hex, FeedCursor, FeedPage, FeedPageQuery, PageCursor}, gazetteer::normalize, post::{FeedFilter, Post}};
+use std::collections::{BTreeMap, HashMap};
+
+/// Longest allowed search, in characters
+pub const SEARCH_QUERY_MAX: usize = 200;
+/// How much each field counts towards a post's score
+const TITLE_WEIGHT: f64 = 3.0;
+const TAG_WEIGHT: f64 = 2.0;
+const LOCATION_WEIGHT: f64 = 1.0;
+/// How much a term counts when only its start was typed
+const PREFIX_WEIGHT: f64 = 0.5;
+/// Shortest query term matched as a prefix; shorter ones must match whole
+const MIN_PREFIX: usize = 2;
+
+/// Crude English suffix stripping, so "bikes", "biking" and "biked" are
+/// all found by "bike"
+fn stem(word: &str) -> String {
+    let mut stem = word.to_string();
+
+    // Plurals first, then tenses, so "strings" ends up with "string"
+    let stages: [&[(&str, &str)]; 2] = [
+        &[("ies", "y"), ("sses", "ss"), ("es", ""), ("s", "")],
+        &[("ing", ""), ("ed", "")],
+    ];
+
+    for stage in stages {
+        for (suffix, replacement) in stage {
+            if let Some(rest) = stem.strip_suffix(suffix) {
+                // Not "glass" or "status"
+                let keep = *suffix == "s" && (rest.ends_with('s') || rest.ends_with('u'));
+
+                if rest.chars().count() >= 3 && !keep {
+                    stem = format!("{}{}", rest, replacement);
+                    break;
+                }
+            }
+        }
+    }
+
+    if stem.chars().count() > 3 && stem.ends_with('e') {
+        stem.pop();
+    }
+
+    stem
+}
+
+/// Stemmed words of `s`
+pub fn tokenize(s: &str) -> Vec<String> {
+    normalize(s).split(' ').filter(|word| !word.is_empty()).map(stem).collect()
+}
+
+/// An inverted index from the stemmed words of a post's title, tags and
+/// location to the UUIDs of the posts that use them
+#[derive(Default, Clone)]
+pub struct SearchIndex {
+    /// Term to its weight in each post that has it, by post UUID
+    terms: BTreeMap<String, HashMap<String, f64>>,
+    /// Post UUID to its terms, for removing it
+    posts: HashMap<String, Vec<String>>,
+}
+
+impl SearchIndex {
+    /// Index `post` as it is now
+    pub fn update(&mut self, post: &Post) {
+        self.remove(&post.uuid);
+
+        let mut weights: HashMap<String, f64> = HashMap::new();
+        let fields = std::iter::once((post.title.as_str(), TITLE_WEIGHT))
+            .chain(post.get_tags().iter().map(|tag| (tag.as_str(), TAG_WEIGHT)))
+            .chain(std::iter::once((post.location_string.as_str(), LOCATION_WEIGHT)));
+
+        for (text, weight) in fields {
+            for term in tokenize(text) {
+                *weights.entry(term).or_default() += weight;
+            }
+        }
+
+        for (term, weight) in weights.iter() {
+            self.terms.entry(term.clone()).or_default().insert(post.uuid.clone(), *weight);
+        }
+        self.posts.insert(post.uuid.clone(), weights.into_keys().collect());
+    }
+
+    pub fn remove(&mut self, uuid: &str) {
+        if let Some(terms) = self.posts.remove(uuid) {
+            for term in terms {
+                if let Some(posts) = self.terms.get_mut(&term) {
+                    posts.remove(uuid);
+                    if posts.is_empty() {
+                        self.terms.remove(&term);
+                    }
+                }
+            }
+        }
+    }
+
+    /// UUID of every post matching all of `terms`, with its score. A term
+    /// matches whole words, or the start of one at a lower weight.
+    ///
+    /// A score depends only on the post and the terms, not on what else is
+    /// indexed, so it can't shift under a cursor as posts come and go.
+    pub fn search(&self, terms: &[String]) -> HashMap<&str, f64> {
+        let mut scores: HashMap<&str, f64> = HashMap::new();
+
+        for (i, term) in terms.iter().enumerate() {
+            // Best score for this term in each post
+            let mut matched: HashMap<&str, f64> = HashMap::new();
+
+            let candidates = self.terms.range(term.clone()..)
+                .take_while(|(indexed, _)| indexed == &term || (term.chars().count() >= MIN_PREFIX && indexed.starts_with(term.as_str())));
+
+            for (indexed, posts) in candidates {
+                let weight = if indexed == term { 1.0 } else { PREFIX_WEIGHT };
+
+                for (uuid, tf) in posts {
+                    let score = matched.entry(uuid.as_str()).or_default();
+                    *score = score.max(tf * weight);
+                }
+            }
+
+            if i == 0 {
+                scores = matched;
+            } else {
+                scores = scores.into_iter()
+                    .filter_map(|(uuid, score)| matched.get(uuid).map(|x| (uuid, score + x)))
+                    .collect();
+            }
+        }
+
+        scores
+    }
+}
+
+/// A position in search results, ordered by score and then as the feed is
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SearchCursor {
+    /// Bits of the non-negative score, which order the same as the score
+    pub score: u64,
+    pub post: FeedCursor,
+}
+
+impl PageCursor for SearchCursor {
+    fn encode(&self) -> String {
+        to_hex(&format!("{}:{}:{}", self.score, self.post.time_posted, self.post.uuid))
+    }
+
+    fn decode(cursor: &str) -> Result<SearchCursor, String> {
+        let invalid = || "Invalid cursor".to_string();
+
+        let decoded = from_hex(cursor).ok_or_else(invalid)?;
+        let mut parts = decoded.splitn(3, ':');
+        let mut next = || parts.next().ok_or_else(invalid);
+
+        Ok(SearchCursor {
+            score: next()?.parse().map_err(|_| invalid())?,
+            post: FeedCursor {
+                time_posted: next()?.parse().map_err(|_| invalid())?,
+                uuid: next()?.to_string(),
+            },
+        })
+    }
+}
+
+impl Data {
+    /// Rebuild `search_index` from the feed
+    pub fn index_search(&mut self) {
+        let mut index = SearchIndex::default();
+        for post in self.feed.iter() {
+            index.update(post);
+        }
+        self.search_index = index;
+    }
+
+    /// A page of the posts matching `filter` and every word of `q`, most
+    /// relevant first
+    pub fn search_posts(&self, q: &str, filter: &FeedFilter, query: &FeedPageQuery) -> Result<FeedPage, String> {
+        filter.check()?;
+
+        if q.chars().count() > SEARCH_QUERY_MAX {
+            return Err(format!("Search must be at most {} characters", SEARCH_QUERY_MAX));
+        }
+
+        let terms = tokenize(q);
+        if terms.is_empty() {
+            return Err("Nothing to search for".to_string());
+        }
+
+        let mut results: Vec<(SearchCursor, &Post)> = self.search_index.search(&terms)
+            .into_iter()
+            .filter_map(|(uuid, score)| Some((self.indexed_post(uuid)?, score)))
+            .filter(|(post, _)| filter.shows(post))
+            .map(|(post, score)| (SearchCursor { score: score.max(0.0).to_bits(), post: FeedCursor::of(post) }, post))
+            .collect();
+
+        results.sort_by(|a, b| b.0.cmp(&a.0));
+
+        paginate(&results, query, FEED_PAGE_SIZE)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::now, post::{PostType, TimeType}};
+
+    fn add_post(data: &mut Data, title: &str, time_posted: u64) -> String {
+        let mut post = Post::new(title.to_string(), PostType::ISO, "owner".to_string(), TimeType::ServiceNow, vec![], String::new());
+        post.publish("owner", 3600).unwrap();
+        post.time_posted = time_posted;
+        data.search_index.update(&post);
+        data.feed.insert(0, post.clone());
+        data.index_feed();
+        post.uuid
+    }
+
+    fn search(data: &Data, after: Option<String>) -> FeedPage {
+        let query = FeedPageQuery { after, limit: Some(3), ..Default::default() };
+        data.search_posts("bike", &FeedFilter::default(), &query).unwrap()
+    }
+
+    #[test]
+    fn pages_hold_still_as_posts_arrive() {
+        let mut data = Data::default();
+        let mut expected = Vec::new();
+        let titles = ["Bike pump", "Bike lock", "Lend a bike", "Bike repair", "Bikes for kids", "Bike helmet"];
+        for (i, title) in titles.into_iter().enumerate() {
+            expected.push(add_post(&mut data, title, now() + i as u64));
+        }
+        add_post(&mut data, "Ladder", now());
+
+        let first = search(&data, None);
+
+        // Makes "bike" a more common term than it was for the first page
+        add_post(&mut data, "Bike rack", now() + 100);
+        add_post(&mut data, "Bike bell", now() + 101);
+
+        let second = search(&data, first.next.clone());
+        assert!(second.next.is_none());
+
+        let mut seen: Vec<String> = first.items.iter().chain(second.items.iter()).map(|post| post.uuid.clone()).collect();
+        seen.sort();
+        expected.sort();
+        assert_eq!(seen, expected);
+    }
+}
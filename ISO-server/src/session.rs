@@ -0,0 +1,142 @@
+use crate::{data::{now, Data}, user::User, view::SessionView};
+use ::serde::{Deserialize, Serialize};
+use openssl::{memcmp, sha::sha256};
+
+/// Only bump `time_last_used` (and persist the user) this often
+pub const LAST_USED_RESOLUTION: u64 = 300;
+
+/// One signed-in device.
+///
+/// The bearer token is handed to the client once, when the session is
+/// created, and only its hash is kept.
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Session {
+    pub id: String,
+    /// Hex SHA-256 of the bearer token
+    pub token_hash: String,
+    /// Client-supplied label, e.g. "iPhone"
+    pub device: String,
+    pub time_created: u64,
+    pub time_last_used: u64,
+    pub time_expires: u64,
+}
+
+impl Session {
+    /// A new session lasting `lifetime` seconds, and its bearer token
+    pub fn new(device: String, lifetime: u64) -> (Session, String) {
+        let token = User::generate_token();
+        let now = now();
+
+        let session = Session {
+            id: uuid::Uuid::new_v4().to_string(),
+            token_hash: hash_token(&token),
+            device,
+            time_created: now,
+            time_last_used: now,
+            time_expires: now + lifetime,
+        };
+
+        (session, token)
+    }
+
+    /// Constant-time comparison against a hashed token
+    pub fn matches(&self, token_hash: &str) -> bool {
+        self.token_hash.len() == token_hash.len()
+            && memcmp::eq(self.token_hash.as_bytes(), token_hash.as_bytes())
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.time_expires <= now()
+    }
+
+    pub fn view(&self) -> SessionView {
+        SessionView {
+            id: self.id.clone(),
+            device: self.device.clone(),
+            time_created: self.time_created,
+            time_last_used: self.time_last_used,
+            time_expires: self.time_expires,
+        }
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    sha256(token.as_bytes()).iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+impl Data {
+    /// Rebuild `session_index` from every user's sessions
+    pub fn index_sessions(&mut self) {
+        self.session_index = self
+            .users
+            .values()
+            .flat_map(|user| user.get_sessions().iter().map(|session| (session.token_hash.clone(), user.uuid.clone())))
+            .collect();
+    }
+
+    /// Resolve a bearer token to its user and session id
+    pub fn authenticate(&mut self, token: &str) -> Result<(User, String), String> {
+        let token_hash = hash_token(token);
+
+        let uuid = self.session_index.get(&token_hash).cloned().ok_or("Invalid token".to_string())?;
+        let mut user = self.users.get(&uuid).cloned().ok_or("Invalid token".to_string())?;
+
+        let session = match user.find_session(&token_hash) {
+            Some(session) => session,
+            None => {
+                // Revoked or expired
+                self.session_index.remove(&token_hash);
+                return Err("Invalid token".to_string());
+            }
+        };
+
+        let session_id = session.id.clone();
+
+        if now() >= session.time_last_used + LAST_USED_RESOLUTION {
+            session.time_last_used = now();
+            self.add_update_user(user.clone())?;
+        }
+
+        Ok((user, session_id))
+    }
+
+    /// Sign `uuid` in on a new device, returning the bearer token
+    pub fn create_session(&mut self, uuid: &str, device: String, lifetime: u64) -> Result<String, String> {
+        let mut user = self.users.get(uuid).cloned().ok_or("User not found".to_string())?;
+
+        let (session, token) = Session::new(device, lifetime);
+        let token_hash = session.token_hash.clone();
+        user.add_session(session);
+
+        self.add_update_user(user)?;
+        self.session_index.insert(token_hash, uuid.to_string());
+
+        Ok(token)
+    }
+
+    pub fn revoke_session(&mut self, uuid: &str, session_id: &str) -> Result<(), String> {
+        let mut user = self.users.get(uuid).cloned().ok_or("User not found".to_string())?;
+
+        let session = user.revoke_session(session_id).ok_or("Session not found".to_string())?;
+
+        self.add_update_user(user)?;
+        self.session_index.remove(&session.token_hash);
+
+        Ok(())
+    }
+
+    /// Sign `uuid` out everywhere, returning how many sessions were revoked
+    pub fn revoke_all_sessions(&mut self, uuid: &str) -> Result<usize, String> {
+        let mut user = self.users.get(uuid).cloned().ok_or("User not found".to_string())?;
+
+        let sessions = user.revoke_all_sessions();
+
+        self.add_update_user(user)?;
+        for session in sessions.iter() {
+            self.session_index.remove(&session.token_hash);
+        }
+
+        Ok(sessions.len())
+    }
+}
@@ -0,0 +1,152 @@
+use ::serde::Deserialize;
+use log::*;
+use serde_json::{json, Value};
+use std::{fs::{self, File, OpenOptions}, io::Write, path::{Path, PathBuf}, time::SystemTime};
+
+/// Backups are named `<file>.<unix seconds>.bak` and live next to the file
+fn backup_path(path: &Path, timestamp: u64) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{}.bak", timestamp));
+    path.with_file_name(name)
+}
+
+/// All backups of `path`, newest first
+pub fn list_backups(path: &Path) -> Vec<PathBuf> {
+    let name = path.file_name().unwrap_or_default().to_string_lossy().to_string();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+
+    let mut backups: Vec<(u64, PathBuf)> = fs::read_dir(&dir)
+        .map(|entries| {
+            entries
+                .filter_map(|entry| entry.ok())
+                .filter_map(|entry| {
+                    let file_name = entry.file_name().to_string_lossy().to_string();
+                    let timestamp = file_name
+                        .strip_prefix(&format!("{}.", name))?
+                        .strip_suffix(".bak")?
+                        .parse::<u64>()
+                        .ok()?;
+                    Some((timestamp, entry.path()))
+                })
+                .collect()
+        })
+        .unwrap_or_default();
+
+    backups.sort_by_key(|(timestamp, _)| std::cmp::Reverse(*timestamp));
+    backups.into_iter().map(|(_, path)| path).collect()
+}
+
+fn sync_dir(path: &Path) {
+    // Make the rename itself durable. Not all platforms can open a directory.
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() { Path::new(".") } else { dir };
+        if let Ok(dir) = File::open(dir) {
+            let _ = dir.sync_all();
+        }
+    }
+}
+
+/// Atomically replace `path` with `contents`.
+///
+/// The data is written to a temp file and fsynced before being renamed over
+/// the live file, so a crash leaves either the old or the new file, never a
+/// mix. The previous live file is kept as a timestamped backup, and only the
+/// newest `keep_backups` backups are retained.
+pub fn write_snapshot(path: &Path, contents: &[u8], keep_backups: usize) -> Result<(), String> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&tmp_path)
+        .map_err(|e| e.to_string())?;
+    file.write_all(contents).map_err(|e| e.to_string())?;
+    file.sync_all().map_err(|e| e.to_string())?;
+    drop(file);
+
+    if keep_backups > 0 && path.exists() {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .expect("Time went backwards")
+            .as_secs();
+
+        if let Err(e) = fs::copy(path, backup_path(path, now)) {
+            warn!("Failed to back up {}: {}", path.display(), e);
+        }
+    }
+
+    fs::rename(&tmp_path, path).map_err(|e| e.to_string())?;
+    sync_dir(path);
+
+    for old in list_backups(path).into_iter().skip(keep_backups) {
+        if let Err(e) = fs::remove_file(&old) {
+            warn!("Failed to remove old backup {}: {}", old.display(), e);
+        }
+    }
+
+    Ok(())
+}
+
+/// Parse `path`. A `lenient` read takes the first JSON value and ignores
+/// anything after it, as builds before atomic snapshots could leave behind.
+fn read_document(path: &Path, lenient: bool) -> Result<Value, String> {
+    let bytes = fs::read(path).map_err(|e| e.to_string())?;
+
+    let document: Value = if lenient {
+        let mut de = serde_json::Deserializer::from_slice(&bytes);
+        // Without `de.end()`, so trailing bytes are not an error
+        Value::deserialize(&mut de).map_err(|e| e.to_string())?
+    } else {
+        serde_json::from_slice(&bytes).map_err(|e| e.to_string())?
+    };
+
+    if !document.is_object() {
+        return Err("Snapshot is not a JSON object".to_string());
+    }
+
+    Ok(document)
+}
+
+/// Read `path` as a raw JSON document, falling back to the newest backup
+/// that parses, and then to whatever of `path` parses leniently.
+///
+/// A missing file with no backups is a fresh install and gives an empty
+/// document. Anything else that can't be recovered is an error.
+pub fn read_snapshot(path: &Path) -> Result<Value, String> {
+    let backups = list_backups(path);
+
+    if !path.exists() && backups.is_empty() {
+        return Ok(json!({}));
+    }
+
+    let primary_error = match read_document(path, false) {
+        Ok(document) => return Ok(document),
+        Err(e) => e,
+    };
+    error!("Failed to read {}: {}", path.display(), primary_error);
+
+    for backup in backups {
+        match read_document(&backup, false) {
+            Ok(document) => {
+                warn!("Recovered database from backup {}", backup.display());
+                return Ok(document);
+            }
+            Err(e) => error!("Failed to read backup {}: {}", backup.display(), e),
+        }
+    }
+
+    // A file written before atomic snapshots may have stale bytes after the
+    // document, and there are no backups of it yet
+    if let Ok(document) = read_document(path, true) {
+        warn!("Loaded {} ignoring trailing data; it will be rewritten on the next save", path.display());
+        return Ok(document);
+    }
+
+    Err(format!("No valid snapshot or backup of {}: {}", path.display(), primary_error))
+}
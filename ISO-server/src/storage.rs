@@ -0,0 +1,567 @@
+use crate::{data::Data, gazetteer::Place, karma::KarmaEntry, message::Message, post::Post, snapshot::*, tags::Tag, user::User, wal::*};
+use ::serde::{Deserialize, Serialize};
+use log::*;
+use rusqlite::{params, Connection, OpenFlags, Transaction};
+use serde_json::{json, Map, Value};
+use std::{path::{Path, PathBuf}, sync::Mutex};
+
+/// Which `Storage` implementation to use, chosen in `config.toml`
+#[derive(Default, Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StorageBackend {
+    #[default] Json,
+    Sqlite,
+}
+
+/// Persistence backend for `Data`.
+///
+/// `Data` is always served from memory. A backend loads it once at startup,
+/// is handed every mutation as it happens, and is asked for a full snapshot
+/// on every save tick. A mutation is only applied in memory once its backend
+/// call has succeeded.
+pub trait Storage: Send + Sync {
+    /// Read the whole dataset as a raw document, before any migrations
+    fn load_document(&self) -> Result<Value, String>;
+    /// Write the whole dataset
+    fn save(&self, data: &Data) -> Result<(), String>;
+
+    fn add_user(&self, user: &User) -> Result<(), String>;
+    fn update_user(&self, user: &User) -> Result<(), String>;
+    /// `owner` is the owner with the new post already added, if they exist
+    fn add_post(&self, post: &Post, owner: Option<&User>) -> Result<(), String>;
+    /// An existing post changed, along with any users it touched
+    fn update_post(&self, post: &Post, users: &[User]) -> Result<(), String>;
+    /// A post was removed, along with its messages and any users it touched
+    fn delete_post(&self, uuid: &str, users: &[User]) -> Result<(), String>;
+    /// A post completed and paid out `entry` between `users`
+    fn settle_post(&self, post: &Post, users: &[User], entry: &KarmaEntry) -> Result<(), String>;
+    fn add_message(&self, message: &Message) -> Result<(), String>;
+    /// `user_uuid` has read the first `count` messages about `post_uuid`
+    fn mark_read(&self, post_uuid: &str, user_uuid: &str, count: usize) -> Result<(), String>;
+    /// A gazetteer place was added or replaced
+    fn put_place(&self, place: &Place) -> Result<(), String>;
+    fn delete_place(&self, id: &str) -> Result<(), String>;
+    /// A tag was registered or its synonyms changed
+    fn put_tag(&self, tag: &Tag) -> Result<(), String>;
+    fn delete_tag(&self, name: &str) -> Result<(), String>;
+}
+
+/// The original `db.json` snapshot file.
+///
+/// Mutations are appended to a write-ahead log next to the snapshot as they
+/// happen, replayed on load, and folded into the snapshot on save.
+pub struct JsonStorage {
+    path: PathBuf,
+    wal: Wal,
+    /// How many timestamped backups to keep next to the file
+    backups: usize,
+}
+
+fn wal_path(path: &Path) -> PathBuf {
+    PathBuf::from(format!("{}.wal", path.display()))
+}
+
+impl JsonStorage {
+    pub fn open(path: &str, backups: usize) -> Result<JsonStorage, String> {
+        let path = PathBuf::from(path);
+        let wal = Wal::open(&wal_path(&path))?;
+
+        Ok(JsonStorage { path, wal, backups })
+    }
+
+    /// The snapshot at `path` with its log replayed on top, without opening
+    /// the log for writing
+    pub fn read(path: &str) -> Result<Value, String> {
+        let path = PathBuf::from(path);
+        let wal_path = wal_path(&path);
+
+        let mut document = read_snapshot(&path)?;
+        let replayed = replay(&wal_path, &mut document)?;
+        if replayed > 0 {
+            info!("Replayed {} events from {}", replayed, wal_path.display());
+        }
+
+        Ok(document)
+    }
+}
+
+impl Storage for JsonStorage {
+    fn load_document(&self) -> Result<Value, String> {
+        JsonStorage::read(&self.path.to_string_lossy())
+    }
+
+    fn save(&self, data: &Data) -> Result<(), String> {
+        let contents = serde_json::to_string_pretty(data).map_err(|e| e.to_string())?;
+        write_snapshot(&self.path, contents.as_bytes(), self.backups)?;
+
+        // Everything in the log is now in the snapshot
+        self.wal.truncate()
+    }
+
+    fn add_user(&self, user: &User) -> Result<(), String> {
+        self.wal.append(&Event::AddUser(user.clone()))
+    }
+
+    fn update_user(&self, user: &User) -> Result<(), String> {
+        self.wal.append(&Event::UpdateUser(user.clone()))
+    }
+
+    fn add_post(&self, post: &Post, owner: Option<&User>) -> Result<(), String> {
+        self.wal.append(&Event::AddPost { post: post.clone(), owner: owner.cloned() })
+    }
+
+    fn update_post(&self, post: &Post, users: &[User]) -> Result<(), String> {
+        self.wal.append(&Event::UpdatePost { post: post.clone(), users: users.to_vec() })
+    }
+
+    fn delete_post(&self, uuid: &str, users: &[User]) -> Result<(), String> {
+        self.wal.append(&Event::DeletePost { uuid: uuid.to_string(), users: users.to_vec() })
+    }
+
+    fn settle_post(&self, post: &Post, users: &[User], entry: &KarmaEntry) -> Result<(), String> {
+        self.wal.append(&Event::SettlePost { post: post.clone(), users: users.to_vec(), entry: entry.clone() })
+    }
+
+    fn add_message(&self, message: &Message) -> Result<(), String> {
+        self.wal.append(&Event::AddMessage(message.clone()))
+    }
+
+    fn mark_read(&self, post_uuid: &str, user_uuid: &str, count: usize) -> Result<(), String> {
+        self.wal.append(&Event::MarkRead { post_uuid: post_uuid.to_string(), user_uuid: user_uuid.to_string(), count })
+    }
+
+    fn put_place(&self, place: &Place) -> Result<(), String> {
+        self.wal.append(&Event::PutPlace(place.clone()))
+    }
+
+    fn delete_place(&self, id: &str) -> Result<(), String> {
+        self.wal.append(&Event::DeletePlace { id: id.to_string() })
+    }
+
+    fn put_tag(&self, tag: &Tag) -> Result<(), String> {
+        self.wal.append(&Event::PutTag(tag.clone()))
+    }
+
+    fn delete_tag(&self, name: &str) -> Result<(), String> {
+        self.wal.append(&Event::DeleteTag { name: name.to_string() })
+    }
+}
+
+/// Embedded SQLite database. Every mutation is written in its own transaction.
+///
+/// Rows hold the same JSON as `db.json`, so both backends share one schema
+/// for `Post` and `User`.
+pub struct SqliteStorage {
+    conn: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+    pub fn open(path: &str) -> Result<SqliteStorage, String> {
+        let conn = Connection::open(path).map_err(|e| e.to_string())?;
+
+        conn.execute_batch(
+            "PRAGMA journal_mode = WAL;
+            PRAGMA synchronous = FULL;
+            CREATE TABLE IF NOT EXISTS users (uuid TEXT PRIMARY KEY, body TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS posts (uuid TEXT PRIMARY KEY, time_posted INTEGER NOT NULL, body TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS meta (key TEXT PRIMARY KEY, body TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS karma_ledger (id TEXT PRIMARY KEY, time INTEGER NOT NULL, body TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS messages (id TEXT PRIMARY KEY, post_uuid TEXT NOT NULL, time_sent INTEGER NOT NULL, body TEXT NOT NULL);
+            CREATE INDEX IF NOT EXISTS messages_post ON messages (post_uuid);
+            CREATE TABLE IF NOT EXISTS message_reads (post_uuid TEXT NOT NULL, user_uuid TEXT NOT NULL, count INTEGER NOT NULL, PRIMARY KEY (post_uuid, user_uuid));
+            CREATE TABLE IF NOT EXISTS places (id TEXT PRIMARY KEY, body TEXT NOT NULL);
+            CREATE TABLE IF NOT EXISTS tags (name TEXT PRIMARY KEY, body TEXT NOT NULL);",
+        )
+        .map_err(|e| e.to_string())?;
+
+        info!("Opened SQLite database at {}", path);
+
+        Ok(SqliteStorage { conn: Mutex::new(conn) })
+    }
+
+    /// An existing database, opened without creating or changing anything.
+    /// `None` if there is no file at `path` yet.
+    pub fn open_read_only(path: &str) -> Result<Option<SqliteStorage>, String> {
+        if !Path::new(path).exists() {
+            return Ok(None);
+        }
+
+        let conn = Connection::open_with_flags(path, OpenFlags::SQLITE_OPEN_READ_ONLY).map_err(|e| e.to_string())?;
+
+        Ok(Some(SqliteStorage { conn: Mutex::new(conn) }))
+    }
+
+    pub fn is_empty(&self) -> Result<bool, String> {
+        let conn = self.conn.lock().map_err(|_| "SQLite connection poisoned".to_string())?;
+
+        conn.query_row(
+            "SELECT NOT EXISTS (SELECT 1 FROM users) AND NOT EXISTS (SELECT 1 FROM posts) AND NOT EXISTS (SELECT 1 FROM meta)",
+            [],
+            |row| row.get(0),
+        )
+        .map_err(|e| e.to_string())
+    }
+
+    /// Seed an empty database from the `db.json` snapshot at `path`, so
+    /// switching backends keeps every user and post. Returns whether
+    /// anything was imported.
+    ///
+    /// The snapshot is copied as it is, and migrated on load like any other
+    /// document.
+    pub fn import_json(&self, path: &str) -> Result<bool, String> {
+        let path = PathBuf::from(path);
+        if !path.exists() || !self.is_empty()? {
+            return Ok(false);
+        }
+
+        // Writes since the last save are only in the log
+        let wal_path = wal_path(&path);
+        if std::fs::metadata(&wal_path).map(|meta| meta.len() > 0).unwrap_or(false) {
+            return Err(format!(
+                "{} has unsaved changes; start once with storage_backend = \"json\" to fold them into {} before switching",
+                wal_path.display(),
+                path.display()
+            ));
+        }
+
+        let document = read_snapshot(&path)?;
+        self.transaction(|tx| import_document(tx, &document))?;
+
+        Ok(true)
+    }
+
+    /// Run `f` inside a single transaction, committing only if it succeeds
+    fn transaction<F>(&self, f: F) -> Result<(), String>
+    where
+        F: FnOnce(&Transaction) -> Result<(), rusqlite::Error>,
+    {
+        let mut conn = self.conn.lock().map_err(|_| "SQLite connection poisoned".to_string())?;
+        let tx = conn.transaction().map_err(|e| e.to_string())?;
+
+        f(&tx).map_err(|e| e.to_string())?;
+
+        tx.commit().map_err(|e| e.to_string())
+    }
+}
+
+fn to_json<T: Serialize>(value: &T) -> Result<String, rusqlite::Error> {
+    serde_json::to_string(value).map_err(|e| rusqlite::Error::ToSqlConversionFailure(Box::new(e)))
+}
+
+fn put_user(tx: &Transaction, user: &User) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO users (uuid, body) VALUES (?1, ?2)",
+        params![user.uuid, to_json(user)?],
+    )?;
+    Ok(())
+}
+
+fn put_post(tx: &Transaction, post: &Post) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO posts (uuid, time_posted, body) VALUES (?1, ?2, ?3)",
+        params![post.uuid, post.time_posted as i64, to_json(post)?],
+    )?;
+    Ok(())
+}
+
+fn put_karma_entry(tx: &Transaction, entry: &KarmaEntry) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR IGNORE INTO karma_ledger (id, time, body) VALUES (?1, ?2, ?3)",
+        params![entry.id, entry.time as i64, to_json(entry)?],
+    )?;
+    Ok(())
+}
+
+fn put_message(tx: &Transaction, message: &Message) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        // Upserted rather than replaced, so rowids keep the order they were sent in
+        "INSERT INTO messages (id, post_uuid, time_sent, body) VALUES (?1, ?2, ?3, ?4)
+        ON CONFLICT (id) DO UPDATE SET body = excluded.body",
+        params![message.id, message.post_uuid, message.time_sent as i64, to_json(message)?],
+    )?;
+    Ok(())
+}
+
+fn put_read(tx: &Transaction, post_uuid: &str, user_uuid: &str, count: usize) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO message_reads (post_uuid, user_uuid, count) VALUES (?1, ?2, ?3)",
+        params![post_uuid, user_uuid, count as i64],
+    )?;
+    Ok(())
+}
+
+fn put_place(tx: &Transaction, place: &Place) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO places (id, body) VALUES (?1, ?2)",
+        params![place.id, to_json(place)?],
+    )?;
+    Ok(())
+}
+
+fn put_tag(tx: &Transaction, tag: &Tag) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO tags (name, body) VALUES (?1, ?2)",
+        params![tag.name, to_json(tag)?],
+    )?;
+    Ok(())
+}
+
+/// Write a raw `Data` document into the tables, the reverse of
+/// `load_document`
+fn import_document(tx: &Transaction, document: &Value) -> Result<(), rusqlite::Error> {
+    let empty = Map::new();
+    let body = |value: &Value| value.to_string();
+
+    for (key, value) in document.as_object().unwrap_or(&empty) {
+        match key.as_str() {
+            "users" => {
+                for (uuid, user) in value.as_object().unwrap_or(&empty) {
+                    tx.execute("INSERT OR REPLACE INTO users (uuid, body) VALUES (?1, ?2)", params![uuid, body(user)])?;
+                }
+            }
+            "feed" => {
+                // Oldest first, so rowids keep the feed's order
+                for post in value.as_array().into_iter().flatten().rev() {
+                    tx.execute(
+                        "INSERT OR REPLACE INTO posts (uuid, time_posted, body) VALUES (?1, ?2, ?3)",
+                        params![post["uuid"].as_str().unwrap_or_default(), post["time_posted"].as_i64().unwrap_or(0), body(post)],
+                    )?;
+                }
+            }
+            "karma_ledger" => {
+                for entry in value.as_array().into_iter().flatten() {
+                    tx.execute(
+                        "INSERT OR IGNORE INTO karma_ledger (id, time, body) VALUES (?1, ?2, ?3)",
+                        params![entry["id"].as_str().unwrap_or_default(), entry["time"].as_i64().unwrap_or(0), body(entry)],
+                    )?;
+                }
+            }
+            "conversations" => {
+                for (post_uuid, conversation) in value.as_object().unwrap_or(&empty) {
+                    for message in conversation["messages"].as_array().into_iter().flatten() {
+                        tx.execute(
+                            "INSERT OR IGNORE INTO messages (id, post_uuid, time_sent, body) VALUES (?1, ?2, ?3, ?4)",
+                            params![message["id"].as_str().unwrap_or_default(), post_uuid, message["time_sent"].as_i64().unwrap_or(0), body(message)],
+                        )?;
+                    }
+                    for (user_uuid, count) in conversation["read"].as_object().unwrap_or(&empty) {
+                        put_read(tx, post_uuid, user_uuid, count.as_u64().unwrap_or(0) as usize)?;
+                    }
+                }
+            }
+            "places" => {
+                for (id, place) in value.as_object().unwrap_or(&empty) {
+                    tx.execute("INSERT OR REPLACE INTO places (id, body) VALUES (?1, ?2)", params![id, body(place)])?;
+                }
+            }
+            "tags" => {
+                for (name, tag) in value.as_object().unwrap_or(&empty) {
+                    tx.execute("INSERT OR REPLACE INTO tags (name, body) VALUES (?1, ?2)", params![name, body(tag)])?;
+                }
+            }
+            _ => put_meta(tx, key, value)?,
+        }
+    }
+
+    Ok(())
+}
+
+/// Top-level fields of `Data` other than the feed, users, ledger, messages,
+/// places and tags
+fn put_meta<T: Serialize>(tx: &Transaction, key: &str, value: &T) -> Result<(), rusqlite::Error> {
+    tx.execute(
+        "INSERT OR REPLACE INTO meta (key, body) VALUES (?1, ?2)",
+        params![key, to_json(value)?],
+    )?;
+    Ok(())
+}
+
+impl Storage for SqliteStorage {
+    fn load_document(&self) -> Result<Value, String> {
+        let conn = self.conn.lock().map_err(|_| "SQLite connection poisoned".to_string())?;
+
+        let read_column = |sql: &str| -> Result<Vec<Value>, String> {
+            let mut stmt = conn.prepare(sql).map_err(|e| e.to_string())?;
+            let rows = stmt.query_map([], |row| row.get::<_, String>(0)).map_err(|e| e.to_string())?;
+
+            let mut values = Vec::new();
+            for body in rows {
+                values.push(serde_json::from_str(&body.map_err(|e| e.to_string())?).map_err(|e| e.to_string())?);
+            }
+            Ok(values)
+        };
+
+        let mut users = Map::new();
+        for user in read_column("SELECT body FROM users")? {
+            let uuid = user["uuid"].as_str().unwrap_or_default().to_string();
+            users.insert(uuid, user);
+        }
+
+        // The feed is kept newest first
+        let feed = read_column("SELECT body FROM posts ORDER BY time_posted DESC, rowid DESC")?;
+
+        let karma_ledger = read_column("SELECT body FROM karma_ledger ORDER BY rowid")?;
+
+        let mut places = Map::new();
+        for place in read_column("SELECT body FROM places")? {
+            let id = place["id"].as_str().unwrap_or_default().to_string();
+            places.insert(id, place);
+        }
+
+        let mut tags = Map::new();
+        for tag in read_column("SELECT body FROM tags")? {
+            let name = tag["name"].as_str().unwrap_or_default().to_string();
+            tags.insert(name, tag);
+        }
+
+        let mut conversations = Map::new();
+        for message in read_column("SELECT body FROM messages ORDER BY rowid")? {
+            let post_uuid = message["post_uuid"].as_str().unwrap_or_default().to_string();
+            let conversation = conversations.entry(post_uuid).or_insert_with(|| json!({ "messages": [], "read": {} }));
+            if let Some(messages) = conversation["messages"].as_array_mut() {
+                messages.push(message);
+            }
+        }
+
+        let mut stmt = conn.prepare("SELECT post_uuid, user_uuid, count FROM message_reads").map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?, row.get::<_, i64>(2)?)))
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            let (post_uuid, user_uuid, count) = row.map_err(|e| e.to_string())?;
+            if let Some(conversation) = conversations.get_mut(&post_uuid) {
+                conversation["read"][user_uuid] = Value::from(count);
+            }
+        }
+
+        let mut document = json!({
+            "feed": feed,
+            "users": users,
+            "karma_ledger": karma_ledger,
+            "conversations": conversations,
+            "places": places,
+            "tags": tags,
+        });
+
+        let mut stmt = conn.prepare("SELECT key, body FROM meta").map_err(|e| e.to_string())?;
+        let rows = stmt
+            .query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))
+            .map_err(|e| e.to_string())?;
+        for row in rows {
+            let (key, body) = row.map_err(|e| e.to_string())?;
+            document[key] = serde_json::from_str(&body).map_err(|e| e.to_string())?;
+        }
+
+        Ok(document)
+    }
+
+    fn save(&self, data: &Data) -> Result<(), String> {
+        self.transaction(|tx| {
+            for user in data.users.values() {
+                put_user(tx, user)?;
+            }
+            for post in data.feed.iter() {
+                put_post(tx, post)?;
+            }
+            for entry in data.karma_ledger.iter() {
+                put_karma_entry(tx, entry)?;
+            }
+            for (post_uuid, conversation) in data.conversations.iter() {
+                for message in conversation.messages.iter() {
+                    put_message(tx, message)?;
+                }
+                for (user_uuid, count) in conversation.read.iter() {
+                    put_read(tx, post_uuid, user_uuid, *count)?;
+                }
+            }
+            for place in data.places.values() {
+                put_place(tx, place)?;
+            }
+            for tag in data.tags.values() {
+                put_tag(tx, tag)?;
+            }
+            put_meta(tx, "pinned_posts", &data.pinned_posts)?;
+            put_meta(tx, "schema_version", &data.schema_version)
+        })
+    }
+
+    fn add_user(&self, user: &User) -> Result<(), String> {
+        self.transaction(|tx| put_user(tx, user))
+    }
+
+    fn update_user(&self, user: &User) -> Result<(), String> {
+        self.transaction(|tx| put_user(tx, user))
+    }
+
+    fn add_post(&self, post: &Post, owner: Option<&User>) -> Result<(), String> {
+        self.transaction(|tx| {
+            put_post(tx, post)?;
+            if let Some(owner) = owner {
+                put_user(tx, owner)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn update_post(&self, post: &Post, users: &[User]) -> Result<(), String> {
+        self.transaction(|tx| {
+            put_post(tx, post)?;
+            for user in users {
+                put_user(tx, user)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn delete_post(&self, uuid: &str, users: &[User]) -> Result<(), String> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM posts WHERE uuid = ?1", params![uuid])?;
+            tx.execute("DELETE FROM messages WHERE post_uuid = ?1", params![uuid])?;
+            tx.execute("DELETE FROM message_reads WHERE post_uuid = ?1", params![uuid])?;
+            for user in users {
+                put_user(tx, user)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn settle_post(&self, post: &Post, users: &[User], entry: &KarmaEntry) -> Result<(), String> {
+        self.transaction(|tx| {
+            put_post(tx, post)?;
+            for user in users {
+                put_user(tx, user)?;
+            }
+            put_karma_entry(tx, entry)
+        })
+    }
+
+    fn add_message(&self, message: &Message) -> Result<(), String> {
+        self.transaction(|tx| put_message(tx, message))
+    }
+
+    fn mark_read(&self, post_uuid: &str, user_uuid: &str, count: usize) -> Result<(), String> {
+        self.transaction(|tx| put_read(tx, post_uuid, user_uuid, count))
+    }
+
+    fn put_place(&self, place: &Place) -> Result<(), String> {
+        self.transaction(|tx| put_place(tx, place))
+    }
+
+    fn delete_place(&self, id: &str) -> Result<(), String> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM places WHERE id = ?1", params![id])?;
+            Ok(())
+        })
+    }
+
+    fn put_tag(&self, tag: &Tag) -> Result<(), String> {
+        self.transaction(|tx| put_tag(tx, tag))
+    }
+
+    fn delete_tag(&self, name: &str) -> Result<(), String> {
+        self.transaction(|tx| {
+            tx.execute("DELETE FROM tags WHERE name = ?1", params![name])?;
+            Ok(())
+        })
+    }
+}
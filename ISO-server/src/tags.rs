@@ -0,0 +1,288 @@
+use crate::{data::{now, Data}, gazetteer::normalize, post::{FeedFilter, Post, PostState}};
+use ::serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Longest allowed tag, in characters
+pub const TAG_MAX: usize = 32;
+/// Most tags a post can have
+pub const POST_TAGS_MAX: usize = 10;
+/// Tags returned by a listing unless asked for fewer
+pub const TAG_LIST_SIZE: usize = 20;
+pub const MAX_TAG_LIST_SIZE: usize = 100;
+/// Window for trending tags unless asked for another
+pub const TRENDING_HOURS: u64 = 24;
+pub const MAX_TRENDING_HOURS: u64 = 24 * 30;
+
+/// A canonical tag and the other spellings folded into it
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Tag {
+    pub name: String,
+    pub synonyms: Vec<String>,
+}
+
+/// An admin's new or updated tag; the name comes from the path
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct TagEdit {
+    pub synonyms: Vec<String>,
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
+}
+
+/// Lowercase words joined by dashes, so "Board Games", " #board_games"
+/// and "board-games" are one tag
+fn clean_tag(raw: &str) -> String {
+    normalize(raw).replace(' ', "-")
+}
+
+/// The singular of a plain English plural, so "books" and "book" are one
+/// tag. Irregular ones are left to synonyms.
+fn singular(tag: &str) -> String {
+    for suffix in ["sses", "xes", "ches", "shes", "zzes"] {
+        if tag.ends_with(suffix) {
+            return tag[..tag.len() - 2].to_string();
+        }
+    }
+
+    let keep = ["ss", "us", "is"].iter().any(|suffix| tag.ends_with(suffix));
+    match tag.strip_suffix('s') {
+        Some(rest) if !keep && rest.chars().count() >= 3 => rest.to_string(),
+        _ => tag.to_string(),
+    }
+}
+
+/// `raw` cleaned up and made singular, without looking at synonyms
+pub fn normalize_tag(raw: &str) -> String {
+    singular(&clean_tag(raw))
+}
+
+impl Data {
+    /// Rebuild `tag_synonyms` from the registry
+    pub fn index_tags(&mut self) {
+        let mut index = HashMap::new();
+        for tag in self.tags.values() {
+            index.insert(tag.name.clone(), tag.name.clone());
+            for synonym in tag.synonyms.iter() {
+                index.insert(synonym.clone(), tag.name.clone());
+            }
+        }
+        self.tag_synonyms = index;
+    }
+
+    /// The canonical form of `raw`, or `None` if there's nothing left of it.
+    /// Registered names and synonyms are looked up as written before being
+    /// made singular, so "clothes" can be a tag.
+    pub fn canonical_tag(&self, raw: &str) -> Option<String> {
+        let cleaned = clean_tag(raw);
+        if cleaned.is_empty() {
+            return None;
+        }
+
+        if let Some(name) = self.tag_synonyms.get(&cleaned) {
+            return Some(name.clone());
+        }
+
+        let singular = singular(&cleaned);
+        Some(self.tag_synonyms.get(&singular).cloned().unwrap_or(singular))
+    }
+
+    /// A post's tags in canonical form, without duplicates
+    pub fn normalize_tags(&self, raw: &[String]) -> Result<Vec<String>, String> {
+        let mut tags: Vec<String> = Vec::new();
+
+        for tag in raw.iter().filter_map(|tag| self.canonical_tag(tag)) {
+            if tag.chars().count() > TAG_MAX {
+                return Err(format!("Tags must be at most {} characters", TAG_MAX));
+            }
+            if !tags.contains(&tag) {
+                tags.push(tag);
+            }
+        }
+
+        if tags.len() > POST_TAGS_MAX {
+            return Err(format!("A post can have at most {} tags", POST_TAGS_MAX));
+        }
+
+        Ok(tags)
+    }
+
+    /// `filter` with its tags in canonical form, to match posts by
+    pub fn canonical_filter(&self, mut filter: FeedFilter) -> FeedFilter {
+        if let Some(tags) = filter.tags.take() {
+            let tags: Vec<String> = tags.split(',').filter_map(|tag| self.canonical_tag(tag)).collect();
+            filter.tags = Some(tags.join(","));
+        }
+
+        filter
+    }
+
+    /// Every registered tag, by name
+    pub fn get_tags(&self) -> Vec<&Tag> {
+        let mut tags: Vec<&Tag> = self.tags.values().collect();
+
+        tags.sort_by(|a, b| a.name.cmp(&b.name));
+        tags
+    }
+
+    /// How many of `posts` use each tag, most used first
+    fn count_tags<'a>(posts: impl Iterator<Item = &'a Post>) -> Vec<TagCount> {
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for post in posts {
+            for tag in post.get_tags() {
+                *counts.entry(tag.as_str()).or_default() += 1;
+            }
+        }
+
+        let mut counts: Vec<TagCount> = counts.into_iter().map(|(tag, count)| TagCount { tag: tag.to_string(), count }).collect();
+        counts.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.tag.cmp(&b.tag)));
+        counts
+    }
+
+    /// Tags on the live feed, most used first. With a `prefix`, only tags
+    /// it starts, or starts a synonym of, including unused registered ones.
+    pub fn tag_counts(&self, prefix: Option<&str>, limit: usize) -> Result<Vec<TagCount>, String> {
+        if limit == 0 || limit > MAX_TAG_LIST_SIZE {
+            return Err(format!("limit must be between 1 and {}", MAX_TAG_LIST_SIZE));
+        }
+
+        let live = FeedFilter::default();
+        let mut counts = Data::count_tags(self.feed.iter().filter(|post| live.shows(post)));
+
+        if let Some(prefix) = prefix.map(clean_tag).filter(|prefix| !prefix.is_empty()) {
+            for tag in self.tags.keys() {
+                if !counts.iter().any(|count| &count.tag == tag) {
+                    counts.push(TagCount { tag: tag.clone(), count: 0 });
+                }
+            }
+
+            let matches = |tag: &str| {
+                tag.starts_with(&prefix)
+                    || self.tags.get(tag).is_some_and(|registered| registered.synonyms.iter().any(|synonym| synonym.starts_with(&prefix)))
+            };
+            counts.retain(|count| matches(&count.tag));
+        }
+
+        counts.truncate(limit);
+        Ok(counts)
+    }
+
+    /// Tags on posts published in the last `hours`, most used first
+    pub fn trending_tags(&self, hours: u64, limit: usize) -> Result<Vec<TagCount>, String> {
+        if hours == 0 || hours > MAX_TRENDING_HOURS {
+            return Err(format!("hours must be between 1 and {}", MAX_TRENDING_HOURS));
+        }
+        if limit == 0 || limit > MAX_TAG_LIST_SIZE {
+            return Err(format!("limit must be between 1 and {}", MAX_TAG_LIST_SIZE));
+        }
+
+        let since = now().saturating_sub(hours * 3600);
+        let recent = self.feed.iter()
+            .filter(|post| post.state != PostState::Draft && post.state != PostState::Cancelled && post.time_posted >= since);
+
+        let mut counts = Data::count_tags(recent);
+        counts.truncate(limit);
+        Ok(counts)
+    }
+
+    /// Register a tag with `synonyms`, replacing any it had. Names and
+    /// synonyms are made singular as posts' tags are. Posts using one of the synonyms are
+    /// retagged.
+    pub fn put_tag(&mut self, name: &str, edit: TagEdit) -> Result<Tag, String> {
+        let name = normalize_tag(name);
+        if name.is_empty() || name.chars().count() > TAG_MAX {
+            return Err(format!("Tags must be 1 to {} characters", TAG_MAX));
+        }
+
+        let mut synonyms: Vec<String> = Vec::new();
+        for synonym in edit.synonyms.iter().map(|synonym| normalize_tag(synonym)) {
+            if !synonym.is_empty() && synonym != name && !synonyms.contains(&synonym) {
+                synonyms.push(synonym);
+            }
+        }
+
+        // Each spelling can only lead to one tag
+        for spelling in std::iter::once(&name).chain(synonyms.iter()) {
+            match self.tag_synonyms.get(spelling) {
+                Some(other) if other != &name => return Err(format!("{} already means {}", spelling, other)),
+                _ => {}
+            }
+        }
+
+        let tag = Tag { name: name.clone(), synonyms };
+
+        self.persist(|s| s.put_tag(&tag))?;
+        self.tags.insert(name, tag.clone());
+        self.index_tags();
+
+        self.retag_posts()?;
+        Ok(tag)
+    }
+
+    /// Drop a tag from the registry. Posts keep it.
+    pub fn delete_tag(&mut self, name: &str) -> Result<(), String> {
+        let name = normalize_tag(name);
+        if !self.tags.contains_key(&name) {
+            return Err("Tag not found".to_string());
+        }
+
+        self.persist(|s| s.delete_tag(&name))?;
+        self.tags.remove(&name);
+        self.index_tags();
+
+        Ok(())
+    }
+
+    /// Bring every post's tags in line with the registry
+    fn retag_posts(&mut self) -> Result<(), String> {
+        let stale: Vec<(Post, Vec<String>)> = self.feed.iter()
+            .filter_map(|post| {
+                let tags = self.normalize_tags(post.get_tags()).ok()?;
+                (&tags != post.get_tags()).then(|| (post.clone(), tags))
+            })
+            .collect();
+
+        for (mut post, tags) in stale {
+            post.set_tags(tags);
+            self.update_post(post, Vec::new())?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::{PostType, TimeType};
+
+    #[test]
+    fn plural_names_are_registered_singular() {
+        let mut data = Data::default();
+
+        let mut post = Post::new("Reading".to_string(), PostType::OSI, "owner".to_string(), TimeType::ItemLoan, vec!["novel".to_string()], String::new());
+        post.publish("owner", 3600).unwrap();
+        data.feed.push(post);
+        data.index_feed();
+
+        let tag = data.put_tag("Books", TagEdit { synonyms: vec!["novels".to_string()] }).unwrap();
+        assert_eq!(tag.name, "book");
+        assert!(data.tags.contains_key("book"));
+
+        for spelling in ["book", "Books", "novels", "novel"] {
+            assert_eq!(data.canonical_tag(spelling).as_deref(), Some("book"), "{}", spelling);
+        }
+        assert_eq!(data.feed[0].get_tags(), &vec!["book".to_string()]);
+
+        // Registering it again by another spelling replaces it
+        data.put_tag("book", TagEdit::default()).unwrap();
+        assert_eq!(data.tags.len(), 1);
+
+        data.delete_tag("BOOKS").unwrap();
+        assert!(data.tags.is_empty());
+    }
+}
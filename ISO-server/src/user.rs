@@ -1,6 +1,6 @@
 use openssl::rand;
 use phonenumber::*;
-use crate::{post::Post, CONFIG, data::{db_clone, db_mut}};
+use crate::{post::Post, data::{db_clone, db_mut, now}, geo::Coordinate, karma::KarmaEntry, session::Session, verification::verification_provider, view::*};
 use serde_json::json;
 use ::serde::{Deserialize, Serialize};
 use reqwest::Client;
@@ -10,18 +10,70 @@ const POSSIBLE_CODE_CHARS: &'static [char] = &[
     'X',
 ];
 
+/// Longest allowed display name, in characters
+pub const DISPLAY_NAME_MAX: usize = 50;
+/// Longest allowed bio, in characters
+pub const BIO_MAX: usize = 500;
+/// Longest allowed avatar reference, in bytes
+pub const AVATAR_MAX: usize = 2048;
+
+/// Why a verification couldn't be started
+#[derive(Clone, Debug, PartialEq)]
+pub enum StartVerificationError {
+    /// Bad phone number, or the provider turned it down
+    Invalid(String),
+    /// The user couldn't be saved
+    Storage(String),
+}
+
+/// Something that happened to a user's posts while they weren't looking
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct Notification {
+    pub time: u64,
+    pub post_uuid: String,
+    pub message: String,
+}
+
 /// User data
 #[derive(Default, Deserialize, Serialize, Clone, Debug)]
 pub struct User {
     pub uuid: String,
-    // Used to authenticate user
-    token: String,
+    /// Signed-in devices, used to authenticate user
+    #[serde(default)]
+    sessions: Vec<Session>,
     /// E.164 phone number
     phone_number: String,
-    current_location: (f64, f64),
+    /// Last location the user's device reported
+    current_location: Option<Coordinate>,
+    /// Cached total of the karma ledger, see `Data::index_karma`
     karma: i32,
+    /// UUIDs of posts this user wrote, oldest first
     posts: Vec<String>,
+    /// UUIDs of posts this user was approved to fulfill, oldest first
+    #[serde(default)]
+    claimed_posts: Vec<String>,
     verified: String,
+    #[serde(default)]
+    notifications: Vec<Notification>,
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    bio: String,
+    /// URL or upload id of the user's picture
+    #[serde(default)]
+    avatar: String,
+    #[serde(default)]
+    time_joined: u64,
+}
+
+/// Requested changes to a profile; `None` leaves a field alone
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+#[serde(default)]
+pub struct ProfileEdit {
+    pub display_name: Option<String>,
+    pub bio: Option<String>,
+    pub avatar: Option<String>,
 }
 
 
@@ -30,22 +82,28 @@ impl User {
     pub fn new(uuid: String, phone_number: String) -> Result<User, String> {
         Ok(User {
             uuid,
-            token: User::generate_token(),
+            sessions: Vec::new(),
             phone_number,
-            current_location: (0.0, 0.0),
+            current_location: None,
             karma: 0,
             posts: Vec::new(),
+            claimed_posts: Vec::new(),
             verified: String::new(),
+            notifications: Vec::new(),
+            display_name: String::new(),
+            bio: String::new(),
+            avatar: String::new(),
+            time_joined: now(),
         })
     }
 
-    pub async fn start_verification(phone_number: String, country: String) -> Result<String, String> {
+    pub async fn start_verification(phone_number: String, country: String) -> Result<String, StartVerificationError> {
         let id: Option<phonenumber::country::Id> = country.parse().ok();
 
         let number = phonenumber::parse(id, phone_number);
 
         if number.is_err() {
-            return Err("Error parsing phone number".to_string());
+            return Err(StartVerificationError::Invalid("Error parsing phone number".to_string()));
         }
 
         let number = number.unwrap();
@@ -53,50 +111,26 @@ impl User {
 	    let valid  = phonenumber::is_valid(&number);
 
         if !valid {
-            return Err("Invalid phone number".to_string());
+            return Err(StartVerificationError::Invalid("Invalid phone number".to_string()));
         }
 
         let phone_number = number.format().mode(Mode::E164).to_string();
 
-        let lock = CONFIG.lock().await;
-
-        let client = reqwest::Client::new();
-
-        // Set custom random 6 digit code
-        
-        let res = client.post(format!("https://verify.twilio.com/v2/Services/{}/Verifications", lock.twilio_service))
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .basic_auth(lock.twilio_sid.clone(), Some(lock.twilio_token.clone()))
-            .form(&[("To", phone_number.clone()), ("Channel", "sms".to_string())])
-            .send()
-            .await;
-
-        if res.is_err() {
-            return Err("Error sending verification request".to_string());
-        }
-
-        let res = res.unwrap();
-
-        let text = res.text().await.unwrap();
-        // parse as json
-        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-
-        println!("{:?}", json);
-        let code = json["url"].as_str();
-
-        if code.is_none() {
-            return Err("Error getting verification status, please wait 10 minutes.".to_string());
-        }
-
-        let code = code.unwrap();
+        let code = verification_provider().await
+            .map_err(StartVerificationError::Invalid)?
+            .start(&phone_number).await
+            .map_err(StartVerificationError::Invalid)?;
 
         let mut db = db_mut().await;
 
         let user = db.get_user_by_number(&phone_number);
 
         if user.is_ok() {
-            let uuid = user.as_ref().unwrap().uuid.clone();
-            user.unwrap().set_verification_code(code.to_string());
+            let mut user = user.unwrap().clone();
+            user.set_verification_code(code.to_string());
+
+            let uuid = user.uuid.clone();
+            db.add_update_user(user).map_err(StartVerificationError::Storage)?;
 
             return Ok(uuid);
         }
@@ -104,14 +138,14 @@ impl User {
         let user = User::new(uuid::Uuid::new_v4().to_string(), phone_number);
 
         if user.is_err() {
-            return Err("Error creating user".to_string());
+            return Err(StartVerificationError::Invalid("Error creating user".to_string()));
         }
 
         let mut user = user.unwrap();
 
         user.set_verification_code(code.to_string());
 
-        let _ = db.add_user(user.clone());
+        db.add_user(user.clone()).map_err(StartVerificationError::Storage)?;
 
         drop(db);
 
@@ -119,32 +153,9 @@ impl User {
     }
 
     pub async fn check_verification(&mut self, code: String) -> Result<User, String> {
-        let client = reqwest::Client::new();
-        let lock = CONFIG.lock().await;
-
-        // Set custom random 6 digit code
-        
-        let res = client.post(format!("https://verify.twilio.com/v2/Services/{}/VerificationCheck", lock.twilio_service))
-            .header("Content-Type", "application/x-www-form-urlencoded")
-            .basic_auth(lock.twilio_sid.clone(), Some(lock.twilio_token.clone()))
-            .form(&[("To", self.phone_number.clone()), ("Code", code)])
-            .send()
-            .await;
-
-        if res.is_err() {
-            return Err("Error sending verification check request".to_string());
-        }
+        let approved = verification_provider().await?.check(&self.phone_number, &code).await?;
 
-        let res = res.unwrap();
-
-        let text = res.text().await.unwrap();
-
-        // parse as json
-        let json: serde_json::Value = serde_json::from_str(&text).unwrap();
-
-        println!("{:?}", json);
-
-        if json["status"].as_str().unwrap() != "approved" {
+        if !approved {
             return Err("Invalid verification code".to_string());
         }
 
@@ -186,10 +197,53 @@ impl User {
         self
     }
 
-    pub fn get_token(&self) -> String {
-        self.token.clone()
+    pub fn get_sessions(&self) -> &Vec<Session> {
+        &self.sessions
+    }
+
+    /// The live session whose token hashes to `token_hash`
+    pub fn find_session(&mut self, token_hash: &str) -> Option<&mut Session> {
+        self.sessions.iter_mut().find(|session| !session.is_expired() && session.matches(token_hash))
+    }
+
+    /// Add a session, dropping any that have expired
+    pub fn add_session(&mut self, session: Session) -> &mut Self {
+        self.sessions.retain(|session| !session.is_expired());
+        self.sessions.push(session);
+
+        self
+    }
+
+    /// Returns the revoked session, if it existed
+    pub fn revoke_session(&mut self, id: &str) -> Option<Session> {
+        let pos = self.sessions.iter().position(|session| session.id == id)?;
+        Some(self.sessions.remove(pos))
+    }
+
+    pub fn revoke_all_sessions(&mut self) -> Vec<Session> {
+        std::mem::take(&mut self.sessions)
     }
     
+    pub fn get_location(&self) -> Option<Coordinate> {
+        self.current_location
+    }
+
+    pub fn set_location(&mut self, location: Coordinate) -> &mut Self {
+        self.current_location = Some(location);
+
+        self
+    }
+
+    pub fn get_karma(&self) -> i32 {
+        self.karma
+    }
+
+    pub fn set_karma(&mut self, karma: i32) -> &mut Self {
+        self.karma = karma;
+
+        self
+    }
+
     pub fn get_phone_number(&self) -> String {
         self.phone_number.clone()
     }
@@ -200,8 +254,93 @@ impl User {
         self
     }
 
+    pub fn get_posts(&self) -> &Vec<String> {
+        &self.posts
+    }
+
+    pub fn get_claimed_posts(&self) -> &Vec<String> {
+        &self.claimed_posts
+    }
+
     pub fn add_claimed_post(&mut self, post: String) -> &mut Self {
-        self.posts.push(post);
+        self.claimed_posts.push(post);
+
+        self
+    }
+
+    pub fn remove_claimed_post(&mut self, post: &str) -> &mut Self {
+        self.claimed_posts.retain(|x| x != post);
+
+        self
+    }
+
+    pub fn remove_post(&mut self, post: &str) -> &mut Self {
+        self.posts.retain(|x| x != post);
+
+        self
+    }
+
+    pub fn public_view(&self) -> PublicUserView {
+        PublicUserView {
+            uuid: self.uuid.clone(),
+            display_name: self.display_name.clone(),
+            bio: self.bio.clone(),
+            avatar: self.avatar.clone(),
+            time_joined: self.time_joined,
+            karma: self.karma,
+        }
+    }
+
+    pub fn self_view(&self) -> UserView {
+        UserView {
+            profile: self.public_view(),
+            phone_number: self.phone_number.clone(),
+            location: self.current_location,
+            // `verified` holds the pending code or Twilio URL until then
+            verified: self.verified == "true",
+            posts: self.posts.clone(),
+            claimed_posts: self.claimed_posts.clone(),
+            notifications: self.notifications.clone(),
+        }
+    }
+
+    pub fn admin_view(&self, karma_ledger: Vec<KarmaEntry>) -> AdminUserView {
+        AdminUserView {
+            user: self.self_view(),
+            sessions: self.sessions.iter().map(|session| session.view()).collect(),
+            karma_ledger,
+        }
+    }
+
+    pub fn edit_profile(&mut self, edit: ProfileEdit) -> Result<(), String> {
+        if let Some(display_name) = edit.display_name {
+            let display_name = display_name.trim().to_string();
+            if display_name.chars().count() > DISPLAY_NAME_MAX {
+                return Err(format!("Display name must be at most {} characters", DISPLAY_NAME_MAX));
+            }
+            self.display_name = display_name;
+        }
+
+        if let Some(bio) = edit.bio {
+            if bio.chars().count() > BIO_MAX {
+                return Err(format!("Bio must be at most {} characters", BIO_MAX));
+            }
+            self.bio = bio;
+        }
+
+        if let Some(avatar) = edit.avatar {
+            let avatar = avatar.trim().to_string();
+            if avatar.len() > AVATAR_MAX {
+                return Err("Avatar reference is too long".to_string());
+            }
+            self.avatar = avatar;
+        }
+
+        Ok(())
+    }
+
+    pub fn notify(&mut self, post_uuid: String, message: String) -> &mut Self {
+        self.notifications.push(Notification { time: now(), post_uuid, message });
 
         self
     }
@@ -0,0 +1,195 @@
+use crate::{data::{now, Config}, user::User, VERIFIER};
+use ::serde::{Deserialize, Serialize};
+use async_trait::async_trait;
+use log::*;
+use std::{collections::HashMap, fs::OpenOptions, io::Write, path::PathBuf, sync::{Arc, Mutex}};
+
+/// Which `VerificationProvider` to use, chosen in `config.toml`
+#[derive(Default, Deserialize, Serialize, Clone, Debug, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum VerificationBackend {
+    #[default] Twilio,
+    Local,
+    Mock,
+}
+
+/// Sends and checks phone verification codes
+#[async_trait]
+pub trait VerificationProvider: Send + Sync {
+    /// Send a code to an E.164 `phone_number`.
+    ///
+    /// Returns a reference to keep on the user while the check is pending.
+    async fn start(&self, phone_number: &str) -> Result<String, String>;
+    /// Whether `code` is the code most recently sent to `phone_number`
+    async fn check(&self, phone_number: &str, code: &str) -> Result<bool, String>;
+}
+
+/// The provider set up at startup
+pub async fn verification_provider() -> Result<Arc<dyn VerificationProvider>, String> {
+    VERIFIER.lock().await.clone().ok_or("Verification is not configured".to_string())
+}
+
+/// Build the provider selected in `config.toml`
+pub fn open_verification_provider(config: &Config) -> Arc<dyn VerificationProvider> {
+    match config.verification_provider {
+        VerificationBackend::Twilio => Arc::new(TwilioProvider::new(config)),
+        VerificationBackend::Local => Arc::new(LocalProvider::new(config.verification_code_ttl)),
+        VerificationBackend::Mock => {
+            let file = if config.mock_code_file.is_empty() {
+                None
+            } else {
+                Some(PathBuf::from(&config.mock_code_file))
+            };
+            Arc::new(MockProvider::new(config.verification_code_ttl, file))
+        }
+    }
+}
+
+/// Twilio Verify, which sends the SMS and keeps the code itself
+pub struct TwilioProvider {
+    sid: String,
+    service: String,
+    token: String,
+    client: reqwest::Client,
+}
+
+impl TwilioProvider {
+    pub fn new(config: &Config) -> TwilioProvider {
+        TwilioProvider {
+            sid: config.twilio_sid.clone(),
+            service: config.twilio_service.clone(),
+            token: config.twilio_token.clone(),
+            client: reqwest::Client::new(),
+        }
+    }
+
+    async fn post(&self, endpoint: &str, form: &[(&str, &str)]) -> Result<serde_json::Value, String> {
+        let res = self.client.post(format!("https://verify.twilio.com/v2/Services/{}/{}", self.service, endpoint))
+            .header("Content-Type", "application/x-www-form-urlencoded")
+            .basic_auth(self.sid.clone(), Some(self.token.clone()))
+            .form(form)
+            .send()
+            .await
+            .map_err(|_| "Error sending verification request".to_string())?;
+
+        let text = res.text().await.map_err(|_| "Error reading verification response".to_string())?;
+
+        serde_json::from_str(&text).map_err(|_| "Error parsing verification response".to_string())
+    }
+}
+
+#[async_trait]
+impl VerificationProvider for TwilioProvider {
+    async fn start(&self, phone_number: &str) -> Result<String, String> {
+        let json = self.post("Verifications", &[("To", phone_number), ("Channel", "sms")]).await?;
+
+        debug!("{:?}", json);
+
+        match json["url"].as_str() {
+            Some(url) => Ok(url.to_string()),
+            None => Err("Error getting verification status, please wait 10 minutes.".to_string()),
+        }
+    }
+
+    async fn check(&self, phone_number: &str, code: &str) -> Result<bool, String> {
+        let json = self.post("VerificationCheck", &[("To", phone_number), ("Code", code)]).await?;
+
+        debug!("{:?}", json);
+
+        Ok(json["status"].as_str() == Some("approved"))
+    }
+}
+
+struct PendingCode {
+    code: String,
+    expires: u64,
+}
+
+/// Generates codes with `User::generate_code` and checks them in process.
+///
+/// Codes aren't delivered anywhere; see `MockProvider` for local development.
+pub struct LocalProvider {
+    codes: Mutex<HashMap<String, PendingCode>>,
+    /// Seconds a code stays valid
+    ttl: u64,
+}
+
+impl LocalProvider {
+    pub fn new(ttl: u64) -> LocalProvider {
+        LocalProvider { codes: Mutex::new(HashMap::new()), ttl }
+    }
+
+    /// Issue a fresh code for `phone_number`, replacing any pending one
+    pub fn issue(&self, phone_number: &str) -> Result<String, String> {
+        let code = User::generate_code();
+
+        let mut codes = self.codes.lock().map_err(|_| "Verification codes poisoned".to_string())?;
+        codes.retain(|_, pending| pending.expires > now());
+        codes.insert(phone_number.to_string(), PendingCode { code: code.clone(), expires: now() + self.ttl });
+
+        Ok(code)
+    }
+}
+
+#[async_trait]
+impl VerificationProvider for LocalProvider {
+    async fn start(&self, phone_number: &str) -> Result<String, String> {
+        self.issue(phone_number)?;
+        Ok("pending".to_string())
+    }
+
+    async fn check(&self, phone_number: &str, code: &str) -> Result<bool, String> {
+        let mut codes = self.codes.lock().map_err(|_| "Verification codes poisoned".to_string())?;
+
+        let valid = match codes.get(phone_number) {
+            Some(pending) => pending.expires > now() && pending.code == code.trim().to_uppercase(),
+            None => false,
+        };
+
+        // Codes are single use
+        if valid {
+            codes.remove(phone_number);
+        }
+
+        Ok(valid)
+    }
+}
+
+/// A `LocalProvider` that writes every code to stdout or a file instead of
+/// sending an SMS
+pub struct MockProvider {
+    local: LocalProvider,
+    file: Option<PathBuf>,
+}
+
+impl MockProvider {
+    pub fn new(ttl: u64, file: Option<PathBuf>) -> MockProvider {
+        MockProvider { local: LocalProvider::new(ttl), file }
+    }
+}
+
+#[async_trait]
+impl VerificationProvider for MockProvider {
+    async fn start(&self, phone_number: &str) -> Result<String, String> {
+        let code = self.local.issue(phone_number)?;
+        let line = format!("Verification code for {}: {}", phone_number, code);
+
+        match &self.file {
+            Some(path) => {
+                let mut file = OpenOptions::new()
+                    .append(true)
+                    .create(true)
+                    .open(path)
+                    .map_err(|e| e.to_string())?;
+                writeln!(file, "{}", line).map_err(|e| e.to_string())?;
+            }
+            None => println!("{}", line),
+        }
+
+        Ok("pending".to_string())
+    }
+
+    async fn check(&self, phone_number: &str, code: &str) -> Result<bool, String> {
+        self.local.check(phone_number, code).await
+    }
+}
@@ -0,0 +1,285 @@
+use crate::{data::Data, geo::Coordinate, karma::{KarmaEntry, KarmaReason}, message::{Conversation, Message}, post::*, user::Notification};
+use ::serde::{Deserialize, Serialize};
+
+// What leaves the server. Routes never serialize `User` or `Post` directly;
+// they pick the view for whoever is asking, so phone numbers, session
+// hashes and verification state can't leak by adding a field.
+
+/// What other users get to see about a user
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct PublicUserView {
+    pub uuid: String,
+    pub display_name: String,
+    pub bio: String,
+    pub avatar: String,
+    pub time_joined: u64,
+    pub karma: i32,
+}
+
+/// What a user gets to see about themselves
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct UserView {
+    #[serde(flatten)]
+    pub profile: PublicUserView,
+    pub phone_number: String,
+    pub location: Option<Coordinate>,
+    pub verified: bool,
+    pub posts: Vec<String>,
+    pub claimed_posts: Vec<String>,
+    pub notifications: Vec<Notification>,
+}
+
+/// A session without its token hash
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct SessionView {
+    pub id: String,
+    pub device: String,
+    pub time_created: u64,
+    pub time_last_used: u64,
+    pub time_expires: u64,
+}
+
+/// One of a user's own sessions, marking the one making the request
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct OwnSessionView {
+    #[serde(flatten)]
+    pub session: SessionView,
+    pub current: bool,
+}
+
+/// What an operator gets to see about a user: everything but token hashes
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct AdminUserView {
+    #[serde(flatten)]
+    pub user: UserView,
+    pub sessions: Vec<SessionView>,
+    pub karma_ledger: Vec<KarmaEntry>,
+}
+
+/// A karma transfer in one user's history
+#[derive(Serialize, Clone, Debug)]
+pub struct KarmaEntryView {
+    pub id: String,
+    pub time: u64,
+    pub reason: KarmaReason,
+    pub post_uuid: Option<String>,
+    /// Change to the user's karma
+    pub delta: i32,
+    /// The other side, only shown to the user themselves
+    pub counterparty: Option<String>,
+}
+
+impl KarmaEntryView {
+    pub fn new(entry: &KarmaEntry, user: &str, viewer: &str) -> KarmaEntryView {
+        let counterparty = if entry.to.as_deref() == Some(user) { &entry.from } else { &entry.to };
+
+        KarmaEntryView {
+            id: entry.id.clone(),
+            time: entry.time,
+            reason: entry.reason.clone(),
+            post_uuid: entry.post_uuid.clone(),
+            delta: entry.delta_for(user),
+            counterparty: if user == viewer { counterparty.clone() } else { None },
+        }
+    }
+}
+
+/// What anyone gets to see about a post
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct PublicPostView {
+    pub uuid: String,
+    pub title: String,
+    pub iso_or_osi: PostType,
+    pub state: PostState,
+    pub location_string: String,
+    pub location: Option<Coordinate>,
+    pub place_id: Option<String>,
+    pub time_posted: u64,
+    pub time_expires: u64,
+    pub time_accepted: Option<u64>,
+    pub time_completed: Option<u64>,
+    pub time_type: TimeType,
+    pub tags: Vec<String>,
+    pub karma_diff: i32,
+    pub views: u64,
+    /// `None` if the owner no longer exists
+    pub owner: Option<PublicUserView>,
+    pub claimed: bool,
+}
+
+/// What the owner and acceptor of a post get to see about it
+#[derive(Default, Deserialize, Serialize, Clone, Debug)]
+pub struct ParticipantPostView {
+    #[serde(flatten)]
+    pub post: PublicPostView,
+    pub acceptor: Option<PublicUserView>,
+    pub claim_requests: Vec<ClaimRequest>,
+    pub owner_completed: bool,
+    pub acceptor_completed: bool,
+    pub revisions: Vec<PostRevision>,
+}
+
+/// A post found by a location search
+#[derive(Serialize, Clone, Debug)]
+pub struct NearbyPostView {
+    #[serde(flatten)]
+    pub post: PostView,
+    pub distance_km: f64,
+}
+
+/// A post as seen by a particular user
+#[derive(Serialize, Clone, Debug)]
+#[serde(untagged)]
+pub enum PostView {
+    Public(PublicPostView),
+    Participant(ParticipantPostView),
+}
+
+impl PostView {
+    /// The view of `post` for `viewer`, or for the public if `None`
+    pub fn new(data: &Data, post: &Post, viewer: Option<&str>) -> PostView {
+        let profile = |uuid: Option<String>| {
+            uuid.and_then(|uuid| data.users.get(&uuid)).map(|user| user.public_view())
+        };
+
+        let public = post.public_view(profile(Some(post.get_owner())));
+
+        match viewer {
+            Some(viewer) if post.get_owner() == viewer || post.get_acceptor().as_deref() == Some(viewer) => {
+                PostView::Participant(post.participant_view(public, profile(post.get_acceptor())))
+            }
+            _ => PostView::Public(public),
+        }
+    }
+
+    pub fn list(data: &Data, posts: &[Post], viewer: Option<&str>) -> Vec<PostView> {
+        posts.iter().map(|post| PostView::new(data, post, viewer)).collect()
+    }
+}
+
+/// One thread in a user's list of conversations
+#[derive(Serialize, Clone, Debug)]
+pub struct ConversationView {
+    pub post: PostView,
+    /// UUID of the acceptor the thread is with, to pick it when paging
+    pub acceptor: String,
+    /// The other participant
+    pub with: Option<PublicUserView>,
+    pub last_message: Option<Message>,
+    pub unread: usize,
+}
+
+impl ConversationView {
+    pub fn new(data: &Data, post: &Post, acceptor: &str, conversation: &Conversation, viewer: &str) -> ConversationView {
+        let other = if post.get_owner() == viewer { acceptor.to_string() } else { post.get_owner() };
+
+        ConversationView {
+            post: PostView::new(data, post, Some(viewer)),
+            acceptor: acceptor.to_string(),
+            with: data.users.get(&other).map(|user| user.public_view()),
+            last_message: conversation.thread(acceptor).last().cloned(),
+            unread: conversation.unread(viewer, acceptor),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{events::{EventKind, UserEvent}, message::Message, session::Session, user::User};
+
+    const CODE: &str = "7QW9XP";
+
+    /// A user with a phone number, a live session and a pending code
+    fn seed_user(uuid: &str, phone_number: &str) -> User {
+        let mut user = User::new(uuid.to_string(), phone_number.to_string()).unwrap();
+        user.add_session(Session::new("iPhone".to_string(), 3600).0);
+        user.set_verification_code(CODE.to_string());
+        user
+    }
+
+    /// An accepted post between two such users, with a message about it
+    fn seed() -> (Data, Post) {
+        let mut data = Data::default();
+
+        for (uuid, phone_number) in [("owner", "+15555550101"), ("acceptor", "+15555550102")] {
+            data.users.insert(uuid.to_string(), seed_user(uuid, phone_number));
+        }
+
+        let mut post = Post::new("Ladder".to_string(), PostType::ISO, "owner".to_string(), TimeType::ItemLoan, vec!["tools".to_string()], "Hoch".to_string());
+        post.publish("owner", 3600).unwrap();
+        post.request_claim("acceptor".to_string()).unwrap();
+        post.approve_claim("owner", "acceptor").unwrap();
+        data.feed.push(post.clone());
+
+        let mut conversation = Conversation::default();
+        conversation.add_message(Message {
+            id: "message".to_string(),
+            post_uuid: post.uuid.clone(),
+            sender: "acceptor".to_string(),
+            body: "On my way".to_string(),
+            time_sent: 1,
+            acceptor: "acceptor".to_string(),
+        });
+        data.conversations.insert(post.uuid.clone(), conversation);
+        data.karma_ledger.push(KarmaEntry::new(KarmaReason::PostCompleted, Some(post.uuid.clone()), Some("owner".to_string()), Some("acceptor".to_string()), 1));
+
+        (data, post)
+    }
+
+    #[test]
+    fn views_leak_no_secrets() {
+        let (data, post) = seed();
+        let conversation = &data.conversations[&post.uuid];
+        let message = conversation.thread("acceptor").last().unwrap().clone();
+        let entry = &data.karma_ledger[0];
+
+        // Everything a route or event stream sends about one user to another
+        let mut public = vec![
+            serde_json::to_string(&data.users["owner"].public_view()).unwrap(),
+            serde_json::to_string(&data.users["acceptor"].public_view()).unwrap(),
+            serde_json::to_string(&ConversationView::new(&data, &post, "acceptor", conversation, "owner")).unwrap(),
+            serde_json::to_string(&ConversationView::new(&data, &post, "acceptor", conversation, "acceptor")).unwrap(),
+            serde_json::to_string(&KarmaEntryView::new(entry, "owner", "acceptor")).unwrap(),
+            serde_json::to_string(&message).unwrap(),
+            serde_json::to_string(&UserEvent { seq: 1, time: 1, kind: EventKind::MessageReceived { message: message.clone() } }).unwrap(),
+        ];
+
+        for viewer in [None, Some("owner"), Some("acceptor"), Some("stranger")] {
+            public.push(serde_json::to_string(&PostView::list(&data, &data.feed, viewer)).unwrap());
+            public.push(serde_json::to_string(&NearbyPostView { post: PostView::new(&data, &post, viewer), distance_km: 0.5 }).unwrap());
+        }
+
+        // Make sure both kinds of post view were covered
+        assert!(public.iter().any(|output| output.contains("claim_requests")));
+        assert!(public.iter().any(|output| !output.contains("claim_requests") && output.contains("\"claimed\"")));
+
+        // And what a user sees about themselves, or an operator about anyone
+        let owner = &data.users["owner"];
+        let sessions: Vec<OwnSessionView> = owner.get_sessions().iter()
+            .map(|session| OwnSessionView { session: session.view(), current: true })
+            .collect();
+        let private = [
+            serde_json::to_string(&owner.self_view()).unwrap(),
+            serde_json::to_string(&sessions).unwrap(),
+            serde_json::to_string(&KarmaEntryView::new(entry, "owner", "owner")).unwrap(),
+            serde_json::to_string(&owner.admin_view(data.karma_ledger.clone())).unwrap(),
+        ];
+
+        let hashes: Vec<String> = data.users.values()
+            .flat_map(|user| user.get_sessions().iter().map(|session| session.token_hash.clone()))
+            .collect();
+
+        for output in public.iter() {
+            for secret in ["phone_number", "+1555555010", "sessions", "counterparty\":\""] {
+                assert!(!output.contains(secret), "{} leaked in {}", secret, output);
+            }
+        }
+
+        for output in public.iter().chain(private.iter()) {
+            for secret in hashes.iter().map(String::as_str).chain(["token_hash", CODE]) {
+                assert!(!output.contains(secret), "{} leaked in {}", secret, output);
+            }
+        }
+    }
+}
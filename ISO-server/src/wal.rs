@@ -0,0 +1,233 @@
+use crate::{gazetteer::Place, karma::KarmaEntry, message::Message, post::Post, tags::Tag, user::User};
+use ::serde::{Deserialize, Serialize};
+use log::*;
+use serde_json::{json, Value};
+use std::{fs::{File, OpenOptions}, io::{BufRead, BufReader, Write}, path::Path, sync::Mutex};
+
+/// A single mutation of `Data`, as written to the write-ahead log.
+///
+/// Events carry the full resulting records rather than the request that
+/// produced them, so replaying one twice gives the same result.
+#[derive(Deserialize, Serialize, Clone, Debug)]
+pub enum Event {
+    AddUser(User),
+    UpdateUser(User),
+    AddPost { post: Post, owner: Option<User> },
+    /// Written by builds before claims needed the owner's approval
+    ClaimPost { post: Post, claimer: User },
+    UpdatePost { post: Post, users: Vec<User> },
+    DeletePost { uuid: String, users: Vec<User> },
+    SettlePost { post: Post, users: Vec<User>, entry: KarmaEntry },
+    AddMessage(Message),
+    MarkRead { post_uuid: String, user_uuid: String, count: usize },
+    PutPlace(Place),
+    DeletePlace { id: String },
+    PutTag(Tag),
+    DeleteTag { name: String },
+}
+
+/// `document[key]` as an array, replacing anything else that is there
+fn array<'a>(document: &'a mut Value, key: &str) -> &'a mut Vec<Value> {
+    if !document[key].is_array() {
+        document[key] = json!([]);
+    }
+    document[key].as_array_mut().unwrap()
+}
+
+fn put_users(document: &mut Value, users: &Value) {
+    for user in users.as_array().into_iter().flatten().chain(users.is_object().then_some(users)) {
+        if let Some(uuid) = user["uuid"].as_str() {
+            document["users"][uuid] = user.clone();
+        }
+    }
+}
+
+fn replace_post(document: &mut Value, post: &Value) {
+    for existing in array(document, "feed").iter_mut() {
+        if existing["uuid"] == post["uuid"] {
+            *existing = post.clone();
+        }
+    }
+}
+
+/// Apply one logged event to a raw `Data` document.
+///
+/// This works on JSON rather than `Data` so that events written by an
+/// older build are migrated along with the snapshot they follow, which is
+/// always of the same schema version: a build saves as soon as it has
+/// migrated, and saving empties the log.
+fn apply(document: &mut Value, event: Value) -> Result<(), String> {
+    let (kind, body) = match event {
+        Value::Object(event) if event.len() == 1 => event.into_iter().next().unwrap(),
+        _ => return Err("Not an event".to_string()),
+    };
+
+    match kind.as_str() {
+        "AddUser" | "UpdateUser" => put_users(document, &body),
+        "AddPost" => {
+            put_users(document, &body["owner"]);
+            let feed = array(document, "feed");
+            if !feed.iter().any(|post| post["uuid"] == body["post"]["uuid"]) {
+                feed.insert(0, body["post"].clone());
+            }
+        }
+        "ClaimPost" => {
+            put_users(document, &body["claimer"]);
+            replace_post(document, &body["post"]);
+        }
+        "UpdatePost" => {
+            put_users(document, &body["users"]);
+            replace_post(document, &body["post"]);
+        }
+        "DeletePost" => {
+            put_users(document, &body["users"]);
+            array(document, "feed").retain(|post| post["uuid"] != body["uuid"]);
+            array(document, "pinned_posts").retain(|uuid| uuid != &body["uuid"]);
+            if let (Some(conversations), Some(uuid)) = (document["conversations"].as_object_mut(), body["uuid"].as_str()) {
+                conversations.remove(uuid);
+            }
+        }
+        "SettlePost" => {
+            put_users(document, &body["users"]);
+            replace_post(document, &body["post"]);
+            let ledger = array(document, "karma_ledger");
+            if !ledger.iter().any(|entry| entry["id"] == body["entry"]["id"]) {
+                ledger.push(body["entry"].clone());
+            }
+        }
+        "AddMessage" => {
+            let post_uuid = body["post_uuid"].as_str().unwrap_or_default();
+            let messages = array(&mut document["conversations"][post_uuid], "messages");
+            if !messages.iter().any(|message| message["id"] == body["id"]) {
+                messages.push(body);
+            }
+        }
+        "MarkRead" => {
+            let post_uuid = body["post_uuid"].as_str().unwrap_or_default();
+            let user_uuid = body["user_uuid"].as_str().unwrap_or_default();
+            document["conversations"][post_uuid]["read"][user_uuid] = body["count"].clone();
+        }
+        "PutPlace" => {
+            let id = body["id"].as_str().unwrap_or_default().to_string();
+            document["places"][id] = body;
+        }
+        "PutTag" => {
+            let name = body["name"].as_str().unwrap_or_default().to_string();
+            document["tags"][name] = body;
+        }
+        "DeletePlace" | "DeleteTag" => {
+            let (table, key) = if kind == "DeletePlace" { ("places", "id") } else { ("tags", "name") };
+            if let (Some(table), Some(key)) = (document[table].as_object_mut(), body[key].as_str()) {
+                table.remove(key);
+            }
+        }
+        _ => return Err(format!("Unknown event {}", kind)),
+    }
+
+    Ok(())
+}
+
+/// Append-only JSONL log of `Event`s since the last snapshot
+pub struct Wal {
+    file: Mutex<File>,
+}
+
+impl Wal {
+    pub fn open(path: &Path) -> Result<Wal, String> {
+        let file = OpenOptions::new()
+            .append(true)
+            .create(true)
+            .open(path)
+            .map_err(|e| e.to_string())?;
+
+        Ok(Wal { file: Mutex::new(file) })
+    }
+
+    /// Durably append an event. Returns only once it is on disk.
+    pub fn append(&self, event: &Event) -> Result<(), String> {
+        let mut line = serde_json::to_string(event).map_err(|e| e.to_string())?;
+        line.push('\n');
+
+        let mut file = self.file.lock().map_err(|_| "WAL poisoned".to_string())?;
+        file.write_all(line.as_bytes()).map_err(|e| e.to_string())?;
+        file.sync_data().map_err(|e| e.to_string())
+    }
+
+    /// Drop every event, once they are all covered by a snapshot
+    pub fn truncate(&self) -> Result<(), String> {
+        let file = self.file.lock().map_err(|_| "WAL poisoned".to_string())?;
+        file.set_len(0).map_err(|e| e.to_string())?;
+        file.sync_all().map_err(|e| e.to_string())
+    }
+}
+
+/// Apply every event in the log at `path` on top of the raw `document`.
+///
+/// A torn final line from a crash mid-append is skipped; it was never
+/// acknowledged to the client.
+pub fn replay(path: &Path, document: &mut Value) -> Result<usize, String> {
+    if !document.is_object() {
+        return Err("Database document is not a JSON object".to_string());
+    }
+
+    let file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => return Ok(0),
+    };
+
+    let mut count = 0;
+
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| e.to_string())?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        match serde_json::from_str::<Value>(&line).map_err(|e| e.to_string()).and_then(|event| apply(document, event)) {
+            Ok(()) => count += 1,
+            Err(e) => warn!("Skipping unreadable WAL entry on line {}: {}", i + 1, e),
+        }
+    }
+
+    Ok(count)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{data::{Config, Data}, migrate::upgrade};
+    use std::fs;
+
+    #[test]
+    fn replayed_events_are_migrated() {
+        let path = std::env::temp_dir().join(format!("iso-{}.wal", uuid::Uuid::new_v4()));
+
+        // As written by a build from before message threads, ending in a
+        // torn line
+        let events = [
+            json!({ "AddMessage": { "id": "m1", "post_uuid": "post", "sender": "helper", "body": "Hi", "time_sent": 1 } }),
+            json!({ "AddMessage": { "id": "m2", "post_uuid": "post", "sender": "owner", "body": "Hello", "time_sent": 2 } }),
+            json!({ "MarkRead": { "post_uuid": "post", "user_uuid": "owner", "count": 1 } }),
+            json!({ "PutTag": { "name": "tool", "synonyms": ["tools"] } }),
+        ];
+        let log: String = events.iter().map(|event| format!("{}\n", event)).collect();
+        fs::write(&path, log + "{\"AddMessage\": {\"id\"").unwrap();
+
+        let mut document = json!({
+            "schema_version": 8,
+            "feed": [{ "uuid": "post", "user_owner": "owner", "user_acceptor": "helper", "state": "Accepted" }],
+        });
+
+        let replayed = replay(&path, &mut document);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(replayed.unwrap(), 4);
+
+        upgrade(&mut document, &Config::default()).unwrap();
+        let data: Data = serde_json::from_value(document).unwrap();
+
+        let conversation = &data.conversations["post"];
+        assert_eq!(conversation.thread("helper").count(), 2);
+        assert_eq!(conversation.read["owner"], 1);
+        assert_eq!(data.tags["tool"].synonyms, vec!["tools"]);
+    }
+}